@@ -0,0 +1,174 @@
+use crate::line_ranges::Row;
+use crate::metrics::str_width;
+use crate::render::RenderOptions;
+use crate::theme::{opaque_color, parse_color, Style, Theme};
+use crate::traversal::{self, Visitor};
+use crate::utils::{expand_tabs, TabExpander};
+use std::ops::Range;
+use tree_sitter_highlight::{Error, Highlight, HighlightEvent};
+
+const RESET: &str = "\x1b[0m";
+
+/// SGR escape sequence for 24-bit colors
+fn escape(style: &Style, background: Option<[u8; 4]>) -> String {
+    let mut codes = Vec::new();
+    if style.bold {
+        codes.push("1".to_string());
+    }
+    if style.italic {
+        codes.push("3".to_string());
+    }
+    if style.underline {
+        codes.push("4".to_string());
+    }
+    if let Some([r, g, b]) = style
+        .color
+        .as_deref()
+        .and_then(|c| opaque_color(c, background))
+    {
+        codes.push(format!("38;2;{};{};{}", r, g, b));
+    }
+    if codes.is_empty() {
+        return String::new();
+    }
+    format!("\x1b[{}m", codes.join(";"))
+}
+
+/// Lines of text, each starting with the style of the highlights open there
+struct AnsiLines<'a> {
+    source: &'a str,
+    styles: Vec<Style>,
+    foreground: Style,
+    background: Option<[u8; 4]>,
+    hl_stack: Vec<Highlight>,
+    tabs: TabExpander,
+    lines: Vec<String>,
+    line: String,
+}
+
+impl AnsiLines<'_> {
+    fn current_style(&self) -> String {
+        let mut style = self.foreground.clone();
+        for hl in &self.hl_stack {
+            style.merge(&self.styles[hl.0]);
+        }
+        escape(&style, self.background)
+    }
+}
+
+impl Visitor for AnsiLines<'_> {
+    fn start_highlight(&mut self, highlight: Highlight) {
+        self.hl_stack.push(highlight);
+        self.line += &self.current_style();
+    }
+
+    fn end_highlight(&mut self) {
+        self.hl_stack.pop();
+        self.line += RESET;
+        self.line += &self.current_style();
+    }
+
+    fn text(&mut self, range: Range<usize>) {
+        let source = self.source;
+        self.line += &self.tabs.expand(&source[range]);
+    }
+
+    fn line_break(&mut self) {
+        self.tabs.new_line();
+        self.line += RESET;
+        let next = self.current_style();
+        self.lines.push(std::mem::replace(&mut self.line, next));
+    }
+}
+
+/// Render highlight events into text colored with ANSI escape sequences.
+/// Styles are reset at the end of every line so that output can be cut into
+/// lines safely
+pub fn render<I>(
+    events: I,
+    source: &str,
+    names: &[String],
+    theme: &Theme,
+    options: &RenderOptions,
+) -> Result<String, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let background = theme.global("background").and_then(parse_color);
+    let gutter = escape(
+        &Style {
+            color: theme.gutter_foreground().map(String::from),
+            ..Style::default()
+        },
+        background,
+    );
+    let mut ansi = AnsiLines {
+        source,
+        styles: names.iter().map(|name| theme.style_for(name)).collect(),
+        foreground: Style {
+            color: theme.global("foreground").map(String::from),
+            ..Style::default()
+        },
+        background,
+        hl_stack: Vec::new(),
+        tabs: TabExpander::new(options.tab_width),
+        lines: Vec::new(),
+        line: String::new(),
+    };
+    ansi.line = ansi.current_style();
+    traversal::walk(events, source, &mut ansi)?;
+    ansi.line += RESET;
+    let mut lines = ansi.lines;
+    lines.push(ansi.line);
+
+    let rows =
+        options
+            .lines
+            .rows(options.start_line, lines.len(), options.ellipsis);
+    let last_shown = rows.iter().rev().find_map(|row| match row {
+        Row::Line(idx) => Some(*idx),
+        Row::Elided => None,
+    });
+    let number_width = if options.line_numbers {
+        (options.start_line + last_shown.unwrap_or(0))
+            .to_string()
+            .len()
+    } else {
+        0
+    };
+    let code_width = traversal::lines(source)
+        .map(|line| str_width(&expand_tabs(line, options.tab_width)))
+        .max();
+    let mut output = String::with_capacity(source.len() * 2);
+    for row in rows {
+        match row {
+            Row::Line(idx) => {
+                if options.line_numbers {
+                    output += &format!(
+                        "{}{:>width$}{} ",
+                        gutter,
+                        options.start_line + idx,
+                        RESET,
+                        width = number_width,
+                    );
+                }
+                output += &lines[idx];
+            }
+            Row::Elided if options.ellipsis => {
+                output += &format!(
+                    "{}{:>width$}{}",
+                    gutter,
+                    "…",
+                    RESET,
+                    width = number_width,
+                );
+            }
+            Row::Elided => {
+                let width = number_width + 1 + code_width.unwrap_or(0);
+                output += &format!("{}{}{}", gutter, "┄".repeat(width), RESET);
+            }
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
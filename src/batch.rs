@@ -0,0 +1,273 @@
+use crate::cache;
+use crate::render::{RenderOptions, Renderer};
+use serde::{Deserialize, Serialize};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Name of the per-item report in the output directory or archive
+pub const REPORT_NAME: &str = "report.json";
+
+/// A list of snippets to render in a single run
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    /// Options applied to every item unless the item overrides them
+    #[serde(default)]
+    pub defaults: HashMap<String, String>,
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Item {
+    /// Output file name, relative to the output directory or archive root
+    pub name: Option<String>,
+    /// File to read the source code from (command-line only)
+    pub path: Option<PathBuf>,
+    /// Inline source code
+    pub code: Option<String>,
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ItemReport {
+    pub name: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct Output {
+    pub name: String,
+    pub result: Result<Vec<u8>, String>,
+}
+
+impl Output {
+    pub fn report(&self) -> ItemReport {
+        ItemReport {
+            name: self.name.clone(),
+            ok: self.result.is_ok(),
+            error: self.result.as_ref().err().cloned(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn parse(raw: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(raw)
+    }
+}
+
+/// Check that the name doesn't escape the output directory
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Name given to the item, or its path or index with the extension of the
+/// output format
+fn output_name(
+    index: usize,
+    item: &Item,
+    defaults: &HashMap<String, String>,
+) -> String {
+    if let Some(name) = &item.name {
+        return name.clone();
+    }
+    let mut params = defaults.clone();
+    params.extend(item.options.clone());
+    let extension = RenderOptions::from_map(&params)
+        .map(|options| options.format.extension())
+        .unwrap_or("out");
+    let name = match &item.path {
+        Some(path) => path.with_extension(extension),
+        None => PathBuf::from(format!("{}.{}", index, extension)),
+    };
+    name.to_string_lossy().into_owned()
+}
+
+/// Mark names that are taken by an earlier item or by `report.json`, which
+/// would overwrite each other in the output. `a//b` and `a/b` are the same
+fn duplicate_names(names: &[String]) -> Vec<bool> {
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    seen.insert(PathBuf::from(REPORT_NAME));
+    names
+        .iter()
+        .map(|name| !seen.insert(Path::new(name).components().collect()))
+        .collect()
+}
+
+fn render_item(
+    item: &Item,
+    name: String,
+    is_duplicate: bool,
+    defaults: &HashMap<String, String>,
+    base_dir: Option<&Path>,
+) -> Output {
+    let mut params = defaults.clone();
+    params.extend(item.options.clone());
+    let result = (|| {
+        if !is_safe_name(&name) {
+            return Err(format!("invalid output name `{}`", name));
+        }
+        if is_duplicate {
+            return Err(format!("duplicate output name `{}`", name));
+        }
+        let options =
+            RenderOptions::from_map(&params).map_err(|e| e.to_string())?;
+        let source_code = match (&item.code, &item.path) {
+            (Some(code), None) => code.clone(),
+            (None, Some(path)) => {
+                let dir = base_dir
+                    .ok_or("`path` items are not allowed here".to_string())?;
+                fs::read_to_string(dir.join(path)).map_err(|e| {
+                    format!("cannot read {}: {}", path.display(), e)
+                })?
+            }
+            _ => {
+                return Err(format!(
+                    "item `{}` must have exactly one of `code` or `path`",
+                    name
+                ));
+            }
+        };
+        cache::render(&Renderer::from_options(options), &source_code)
+            .map(|(_, data)| data.as_ref().clone())
+            .map_err(|e| e.to_string())
+    })();
+    if let Err(err) = &result {
+        log::warn!("Batch item `{}` failed: {}", name, err);
+    }
+    Output { name, result }
+}
+
+/// Text of a panic raised with a message, as `panic!` does
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown error")
+}
+
+/// Render every item of the manifest using `workers` threads. Failed items
+/// are reported in their `Output` and don't affect the others, even if
+/// rendering panics. Items whose name is already taken fail. `path` items
+/// are resolved relative to `base_dir` and rejected if it's `None`
+pub fn run(
+    manifest: Manifest,
+    base_dir: Option<PathBuf>,
+    workers: usize,
+) -> Vec<Output> {
+    let item_count = manifest.items.len();
+    let names: Vec<_> = manifest
+        .items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| output_name(index, item, &manifest.defaults))
+        .collect();
+    let duplicates = Arc::new(duplicate_names(&names));
+    let names = Arc::new(names);
+    let manifest = Arc::new(manifest);
+    let next_index = Arc::new(Mutex::new(0));
+    let outputs = Arc::new(Mutex::new(Vec::with_capacity(item_count)));
+    let handles: Vec<_> = (0..workers.max(1).min(item_count))
+        .map(|_| {
+            let manifest = manifest.clone();
+            let next_index = next_index.clone();
+            let outputs = outputs.clone();
+            let base_dir = base_dir.clone();
+            let names = names.clone();
+            let duplicates = duplicates.clone();
+            thread::spawn(move || loop {
+                let index = {
+                    let mut next_index = next_index.lock().unwrap();
+                    let index = *next_index;
+                    *next_index += 1;
+                    index
+                };
+                let item = match manifest.items.get(index) {
+                    Some(item) => item,
+                    None => break,
+                };
+                let name = names[index].clone();
+                let output = panic::catch_unwind(AssertUnwindSafe(|| {
+                    render_item(
+                        item,
+                        name.clone(),
+                        duplicates[index],
+                        &manifest.defaults,
+                        base_dir.as_deref(),
+                    )
+                }))
+                .unwrap_or_else(|payload| {
+                    let message = panic_message(payload.as_ref());
+                    log::error!("Batch item `{}` panicked: {}", name, message);
+                    Output {
+                        name,
+                        result: Err(format!("rendering panicked: {}", message)),
+                    }
+                });
+                outputs.lock().unwrap().push((index, output));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Batch worker panicked");
+    }
+
+    let mut outputs = Arc::try_unwrap(outputs)
+        .ok()
+        .expect("Batch workers are finished")
+        .into_inner()
+        .unwrap();
+    outputs.sort_by_key(|(index, _)| *index);
+    outputs.into_iter().map(|(_, output)| output).collect()
+}
+
+pub fn build_report(outputs: &[Output]) -> Vec<u8> {
+    let report: Vec<_> = outputs.iter().map(Output::report).collect();
+    serde_json::to_vec_pretty(&report).unwrap()
+}
+
+/// Pack successfully rendered items and `report.json` into a tar archive
+pub fn write_tar(outputs: &[Output]) -> std::io::Result<Vec<u8>> {
+    let mut archive = tar::Builder::new(Vec::new());
+    let mut append = |name: &str, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, name, data)
+    };
+    for output in outputs {
+        if let Ok(data) = &output.result {
+            append(&output.name, data)?;
+        }
+    }
+    append(REPORT_NAME, &build_report(outputs))?;
+    archive.into_inner()
+}
+
+/// Write successfully rendered items and `report.json` into `dir`
+pub fn write_dir(outputs: &[Output], dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for output in outputs {
+        if let Ok(data) = &output.result {
+            let path = dir.join(&output.name);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, data)?;
+        }
+    }
+    fs::write(dir.join(REPORT_NAME), build_report(outputs))
+}
@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+use tree_sitter_highlight_server::batch::{self, Manifest};
+use tree_sitter_highlight_server::config::{self, Config};
+use tree_sitter_highlight_server::fonts;
+use tree_sitter_highlight_server::utils::language_for_path;
+use tree_sitter_highlight_server::{RenderOptions, Renderer};
+
+const USAGE: &str = "\
+Usage: ts-highlight render [FILE] [OPTIONS]
+       ts-highlight batch MANIFEST [-o DIR|FILE.tar] [--workers N]
+
+Render FILE (or stdin, if FILE is omitted) to an image.
+Batch mode renders every item of a JSON MANIFEST (`-` for stdin) into DIR
+or a tar archive (stdout by default) along with `report.json`.
+
+Options:
+    -l, --lang <NAME>      Language of the source (guessed from FILE)
+    -t, --theme <NAME>     Theme from the stylesheets directory
+    -f, --format <FORMAT>  Output format: png, jpeg, webp, svg, pdf, html,
+                           ansi, latex, rtf, json, lsp-semantic-tokens
+    -o, --output <FILE>    Write result to FILE instead of stdout
+    -h, --help             Print this message
+
+Rendering options, same as parameters of the HTTP API:
+    --line_numbers <BOOL>      Number lines (default: true)
+    --start_line <N>           Number of the first line (default: 1)
+    --highlight_lines <LINES>  Lines to highlight, e.g. `3,7-9`
+    --dim <BOOL>               Fade out lines that are not highlighted
+    --lines <LINES>            Render only these lines, e.g. `120-160`
+    --ellipsis <BOOL>          Mark lines left out by `--lines` with `…`
+    --tab_width <N>            Columns between tab stops (default: 4)
+    --wrap <N>                 Wrap lines longer than N columns (SVG, PNG)
+    --font <FAMILY>            Font from the fonts directory
+    --ligatures <BOOL>         Join glyphs into ligatures (default: true)
+    --font_features <TAGS>     OpenType features, e.g. `ss01,zero,-calt`
+    --embed_font <BOOL>        Embed the font subset into SVG output
+    --text_as_paths <BOOL>     Convert text of SVG output to outlines
+    --font_size <PX>           Keep font size, sizing the picture to fit
+    --padding <PX>             Space around the code
+    --min_width <PX>           Lower bound of the width with `--font_size`
+    --max_width <PX>           Upper bound of the width with `--font_size`
+    --scale <N>                Zoom of raster output, e.g. 2 for retina
+    --dpi <N>                  Zoom of raster output relative to 96 DPI
+    --quality <N>              Quality of JPEG and WebP output, 1-100
+    --compression <LEVEL>      PNG compression: fast, default, best
+    --palette <BOOL>           Reduce PNG output to 256 colors
+    --lossless <BOOL>          Lossless WebP output
+    --page_lines <N>           Rows per page of PDF output (default: one page)
+    --window <STYLE>           Window title bar: none, macos, windows
+    --title <TEXT>             Title of the window, e.g. a file name
+    --margin <PX>              Space around the window
+    --radius <PX>              Corner radius of the window
+    --shadow <BOOL>            Drop shadow under the window
+    --outer_background <COLOR> Color around the window (default: transparent)
+
+PDF output has no window frame and doesn't wrap or shape text, so it rejects
+`--window`, `--margin`, `--radius`, `--shadow`, `--outer_background`, `--wrap`
+and `--font_features`; `--title` sets the document title.
+
+Any other `--key value` pair is passed through as the HTTP API option `key`.
+Defaults are read from the config file, see HIGHLIGHT_CONFIG.";
+
+struct Args {
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    params: HashMap<String, String>,
+    /// `-h` or `--help` in place of an option
+    help: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut input = None;
+    let mut output = None;
+    let mut params = HashMap::new();
+    let mut help = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let key = match arg.as_str() {
+            "-l" => "lang",
+            "-t" => "theme",
+            "-f" => "format",
+            "-o" | "--output" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("missing value for `{}`", arg))?;
+                output = Some(PathBuf::from(value));
+                continue;
+            }
+            "-h" | "--help" => {
+                help = true;
+                continue;
+            }
+            "-" => continue,
+            _ if arg.starts_with("--") => &arg[2..],
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option `{}`", arg));
+            }
+            _ => {
+                if input.is_some() {
+                    return Err(format!("unexpected argument `{}`", arg));
+                }
+                input = Some(PathBuf::from(arg));
+                continue;
+            }
+        };
+        let (key, value) = match key.find('=') {
+            Some(idx) => (&key[..idx], key[idx + 1..].to_string()),
+            None => (
+                key,
+                iter.next()
+                    .ok_or_else(|| format!("missing value for `{}`", arg))?
+                    .clone(),
+            ),
+        };
+        params.insert(key.to_string(), value);
+    }
+    Ok(Args {
+        input,
+        output,
+        params,
+        help,
+    })
+}
+
+fn read_source(input: Option<&Path>) -> io::Result<String> {
+    match input {
+        Some(path) => fs::read_to_string(path),
+        None => {
+            let mut source = String::new();
+            io::stdin().read_to_string(&mut source)?;
+            Ok(source)
+        }
+    }
+}
+
+fn render(args: &[String]) -> Result<(), String> {
+    let mut args = parse_args(args)?;
+    if args.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if !args.params.contains_key("lang") {
+        let language = args
+            .input
+            .as_deref()
+            .and_then(language_for_path)
+            .ok_or("cannot guess the language, specify it with `--lang`")?;
+        args.params.insert("lang".to_string(), language.to_string());
+    }
+    let options =
+        RenderOptions::from_map(&args.params).map_err(|e| e.to_string())?;
+    let source_code =
+        read_source(args.input.as_deref()).map_err(|e| match &args.input {
+            Some(path) => format!("cannot read {}: {}", path.display(), e),
+            None => format!("cannot read stdin: {}", e),
+        })?;
+    let image = Renderer::from_options(options)
+        .render(&source_code)
+        .map_err(|e| e.to_string())?;
+    match &args.output {
+        Some(path) => fs::write(path, image)
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e)),
+        None => io::stdout()
+            .write_all(&image)
+            .map_err(|e| format!("cannot write stdout: {}", e)),
+    }
+}
+
+fn render_batch(args: &[String]) -> Result<(), String> {
+    let args = parse_args(args)?;
+    if args.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if let Some(key) = args.params.keys().find(|key| *key != "workers") {
+        return Err(format!("unknown option `--{}`", key));
+    }
+    let workers = match args.params.get("workers") {
+        Some(n) => n
+            .parse()
+            .map_err(|_| format!("invalid number of workers `{}`", n))?,
+        None => config::get().limits.batch_workers,
+    };
+    let raw = match &args.input {
+        Some(path) => fs::read(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?,
+        None => {
+            let mut raw = Vec::new();
+            io::stdin()
+                .read_to_end(&mut raw)
+                .map_err(|e| format!("cannot read stdin: {}", e))?;
+            raw
+        }
+    };
+    let mut manifest = Manifest::parse(&raw)
+        .map_err(|e| format!("invalid manifest: {}", e))?;
+    // Paths in the manifest are relative to the manifest itself
+    let base_dir = args
+        .input
+        .as_deref()
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let has_default_lang = manifest.defaults.contains_key("lang");
+    for item in manifest.items.iter_mut() {
+        if has_default_lang || item.options.contains_key("lang") {
+            continue;
+        }
+        if let Some(language) = item.path.as_deref().and_then(language_for_path)
+        {
+            item.options.insert("lang".into(), language.into());
+        }
+    }
+
+    let outputs = batch::run(manifest, Some(base_dir), workers);
+    let failed = outputs.iter().filter(|o| o.result.is_err()).count();
+    match &args.output {
+        Some(path) if path.extension().map_or(true, |ext| ext != "tar") => {
+            batch::write_dir(&outputs, path).map_err(|e| {
+                format!("cannot write {}: {}", path.display(), e)
+            })?
+        }
+        output => {
+            let archive = batch::write_tar(&outputs)
+                .map_err(|e| format!("cannot build archive: {}", e))?;
+            match output {
+                Some(path) => fs::write(path, archive).map_err(|e| {
+                    format!("cannot write {}: {}", path.display(), e)
+                })?,
+                None => io::stdout()
+                    .write_all(&archive)
+                    .map_err(|e| format!("cannot write stdout: {}", e))?,
+            }
+        }
+    }
+    for output in outputs.iter() {
+        if let Err(err) = &output.result {
+            eprintln!("{}: {}", output.name, err);
+        }
+    }
+    eprintln!(
+        "Rendered {}/{} items",
+        outputs.len() - failed,
+        outputs.len()
+    );
+    if failed > 0 {
+        return Err(format!("{} item(s) failed", failed));
+    }
+    Ok(())
+}
+
+fn run(args: &[String]) -> i32 {
+    let result = match args.first().map(String::as_str) {
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return 0;
+        }
+        Some("render") => render(&args[1..]),
+        Some("batch") => render_batch(&args[1..]),
+        _ => Err(format!("unknown command\n\n{}", USAGE)),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(msg) => {
+            eprintln!("error: {}", msg);
+            1
+        }
+    }
+}
+
+fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    };
+    config.init_logger();
+    config::init(config);
+    if let Err(err) = fonts::init() {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+    let args: Vec<_> = env::args().skip(1).collect();
+    process::exit(run(&args));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "ts-highlight-{}-{}",
+            name,
+            process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn arguments() {
+        let parsed = parse_args(&args(&[
+            "main.rs",
+            "-t",
+            "ayu-mirage",
+            "--format=svg",
+            "--line_numbers",
+            "false",
+            "-o",
+            "out.svg",
+        ]))
+        .unwrap();
+        assert_eq!(parsed.input, Some(PathBuf::from("main.rs")));
+        assert_eq!(parsed.output, Some(PathBuf::from("out.svg")));
+        assert_eq!(parsed.params["theme"], "ayu-mirage");
+        assert_eq!(parsed.params["format"], "svg");
+        assert_eq!(parsed.params["line_numbers"], "false");
+
+        assert!(parse_args(&args(&["--theme"])).is_err());
+        assert!(parse_args(&args(&["-x", "1"])).is_err());
+        assert!(parse_args(&args(&["a.rs", "b.rs"])).is_err());
+
+        // Help only where an option can be, not as a value
+        assert!(parse_args(&args(&["main.rs", "-h"])).unwrap().help);
+        let parsed = parse_args(&args(&["--title", "-h"])).unwrap();
+        assert!(!parsed.help);
+        assert_eq!(parsed.params["title"], "-h");
+    }
+
+    #[test]
+    fn render_command() {
+        assert_eq!(run(&args(&["frobnicate"])), 1);
+        assert_eq!(run(&args(&["render", "--help"])), 0);
+        // `-h` is the title here, and there's no language to render with
+        assert_eq!(run(&args(&["render", "--title", "-h"])), 1);
+        // No file to guess the language from
+        assert_eq!(run(&args(&["render"])), 1);
+
+        let dir = temp_dir("render");
+        let input = dir.join("main.rs");
+        let output = dir.join("main.svg");
+        fs::write(&input, "fn main() {}\n").unwrap();
+        let status = run(&args(&[
+            "render",
+            input.to_str().unwrap(),
+            "-f",
+            "svg",
+            "-o",
+            output.to_str().unwrap(),
+        ]));
+        assert_eq!(status, 0);
+        assert!(fs::read_to_string(&output).unwrap().starts_with("<svg"));
+        assert_eq!(run(&args(&["render", "missing.rs"])), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batch_command() {
+        let dir = temp_dir("batch");
+        fs::write(dir.join("main.rs"), "fn main() {}\n").unwrap();
+        let manifest = dir.join("manifest.json");
+        fs::write(
+            &manifest,
+            r#"{
+                "defaults": {"format": "svg"},
+                "items": [
+                    {"path": "main.rs"},
+                    {"name": "inline.svg", "code": "let x = 1;",
+                     "options": {"lang": "rust"}},
+                    {"name": "broken.svg", "code": "x", "options": {}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let output = dir.join("out");
+        let status = run(&args(&[
+            "batch",
+            manifest.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ]));
+        // The item without a language fails alone
+        assert_eq!(status, 1);
+        assert!(output.join("main.svg").is_file());
+        assert!(output.join("inline.svg").is_file());
+        assert!(!output.join("broken.svg").exists());
+        let report = fs::read_to_string(output.join("report.json")).unwrap();
+        assert!(report.contains("broken.svg"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use crate::config;
+use crate::render::{Error, Renderer};
+use crate::theme::Theme;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+/// Rendered images keyed by [`cache_key`], kept in memory and optionally
+/// persisted to a directory
+pub struct RenderCache {
+    memory: Mutex<LruCache<String, Arc<Vec<u8>>>>,
+    disk: Option<DiskCache>,
+}
+
+/// Entries persisted to a directory, removed least recently used first once
+/// their files add up to more than `max_bytes`
+struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<DiskIndex>,
+}
+
+struct DiskIndex {
+    /// File sizes by key, in order of use
+    sizes: LruCache<String, u64>,
+    bytes: u64,
+}
+
+static CACHE: Lazy<Option<RenderCache>> = Lazy::new(|| {
+    let config = &config::get().cache;
+    if !config.enabled {
+        return None;
+    }
+    if let Some(dir) = &config.dir {
+        if let Err(err) = fs::create_dir_all(dir) {
+            log::error!("Cannot create cache dir {}: {}", dir.display(), err);
+        }
+    }
+    Some(RenderCache::new(
+        config.capacity,
+        config.dir.clone(),
+        config.max_dir_bytes,
+    ))
+});
+
+/// Hash everything the output depends on, including the server version and
+/// the contents of the theme file
+pub fn cache_key(source_code: &str, renderer: &Renderer) -> String {
+    let config = config::get();
+    let theme = Theme::path(&renderer.options().theme)
+        .and_then(|path| fs::read(path).ok())
+        .unwrap_or_default();
+    let parts = [
+        env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+        // Debug output lists every field, so new options are picked up
+        format!("{:?}", renderer.options()).into_bytes(),
+        format!("{:?}", config.defaults).into_bytes(),
+        format!("{:?}", config.assets).into_bytes(),
+        theme,
+    ];
+    let mut hasher = Sha256::new();
+    for part in parts
+        .iter()
+        .map(Vec::as_slice)
+        .chain(Some(source_code.as_bytes()))
+    {
+        hasher.update(&(part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl RenderCache {
+    /// `max_dir_bytes` of 0 is unlimited
+    pub fn new(
+        capacity: usize,
+        dir: Option<PathBuf>,
+        max_dir_bytes: u64,
+    ) -> Self {
+        Self {
+            memory: Mutex::new(LruCache::new(capacity)),
+            disk: dir.map(|dir| DiskCache::open(dir, max_dir_bytes)),
+        }
+    }
+
+    /// The global cache, `None` if it's disabled in the config
+    pub fn global() -> Option<&'static RenderCache> {
+        CACHE.as_ref()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
+        if let Some(data) = self.memory.lock().unwrap().get(key) {
+            return Some(data.clone());
+        }
+        let data = Arc::new(self.disk.as_ref()?.get(key)?);
+        self.memory
+            .lock()
+            .unwrap()
+            .put(key.to_string(), data.clone());
+        Some(data)
+    }
+
+    pub fn insert(&self, key: &str, data: Arc<Vec<u8>>) {
+        if let Some(disk) = &self.disk {
+            disk.insert(key, &data);
+        }
+        self.memory.lock().unwrap().put(key.to_string(), data);
+    }
+}
+
+impl DiskCache {
+    /// Index the files already in `dir`, oldest modification first, since
+    /// that's the closest to their last use
+    fn open(dir: PathBuf, max_bytes: u64) -> Self {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            if name.ends_with(".tmp") {
+                // Left over from an interrupted write
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+            files.push((modified, name, metadata.len()));
+        }
+        files.sort();
+
+        let mut index = DiskIndex {
+            sizes: LruCache::unbounded(),
+            bytes: 0,
+        };
+        for (_, name, size) in files {
+            index.bytes += size;
+            index.sizes.put(name, size);
+        }
+        let cache = Self {
+            dir,
+            max_bytes,
+            index: Mutex::new(index),
+        };
+        cache.evict(&mut cache.index.lock().unwrap());
+        cache
+    }
+
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let data = fs::read(self.dir.join(key)).ok();
+        let mut index = self.index.lock().unwrap();
+        match &data {
+            Some(_) => {
+                index.sizes.get(key);
+            }
+            None => {
+                if let Some(size) = index.sizes.pop(key) {
+                    index.bytes -= size;
+                }
+            }
+        }
+        data
+    }
+
+    fn insert(&self, key: &str, data: &[u8]) {
+        // Write to a temporary file first so readers never see
+        // a partially written entry
+        let tmp_path = self.dir.join(format!("{}.tmp", key));
+        let result = fs::write(&tmp_path, data)
+            .and_then(|_| fs::rename(&tmp_path, self.dir.join(key)));
+        if let Err(err) = result {
+            log::warn!("Cannot persist cache entry {}: {}", key, err);
+            return;
+        }
+        let mut index = self.index.lock().unwrap();
+        let size = data.len() as u64;
+        if let Some(old_size) = index.sizes.put(key.to_string(), size) {
+            index.bytes -= old_size;
+        }
+        index.bytes += size;
+        self.evict(&mut index);
+    }
+
+    /// Remove least recently used files until the rest fit in `max_bytes`
+    fn evict(&self, index: &mut DiskIndex) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        while index.bytes > self.max_bytes {
+            let (key, size) = match index.sizes.pop_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+            index.bytes -= size;
+            if let Err(err) = fs::remove_file(self.dir.join(&key)) {
+                log::warn!("Cannot remove cache entry {}: {}", key, err);
+            }
+        }
+    }
+}
+
+/// Render through the global cache. Returns the cache key along with the
+/// image, which is served from the cache without rendering on a hit
+pub fn render(
+    renderer: &Renderer,
+    source_code: &str,
+) -> Result<(String, Arc<Vec<u8>>), Error> {
+    let key = cache_key(source_code, renderer);
+    let data = render_with_key(&key, renderer, source_code)?;
+    Ok((key, data))
+}
+
+/// [`render`] with the cache key computed already by [`cache_key`]
+pub fn render_with_key(
+    key: &str,
+    renderer: &Renderer,
+    source_code: &str,
+) -> Result<Arc<Vec<u8>>, Error> {
+    let cache = match RenderCache::global() {
+        Some(cache) => cache,
+        None => return Ok(Arc::new(renderer.render(source_code)?)),
+    };
+    if let Some(data) = cache.get(key) {
+        log::info!("Cache hit {}", key);
+        return Ok(data);
+    }
+    let data = Arc::new(renderer.render(source_code)?);
+    cache.insert(key, data.clone());
+    Ok(data)
+}
@@ -0,0 +1,478 @@
+use crate::render::OutputFormat;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fmt, fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Environment variable with the path to the config file
+pub const CONFIG_PATH_VAR: &str = "HIGHLIGHT_CONFIG";
+/// Config file used when `HIGHLIGHT_CONFIG` is not set, if it exists
+pub const DEFAULT_CONFIG_PATH: &str = "highlight.toml";
+/// Prefix of variables overriding config values, e.g. `HIGHLIGHT_SERVER_BIND`
+/// overrides `bind` in the `[server]` section
+pub const ENV_PREFIX: &str = "HIGHLIGHT_";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub limits: LimitsConfig,
+    pub defaults: DefaultsConfig,
+    pub assets: AssetsConfig,
+    pub formats: FormatsConfig,
+    pub cache: CacheConfig,
+    pub snippets: SnippetsConfig,
+    pub log: LogConfig,
+    /// Problems found while loading, logged once the logger is set up
+    #[serde(skip)]
+    warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub batch_workers: usize,
+    pub max_batch_items: usize,
+    /// Limits below are disabled when set to 0
+    pub max_body_bytes: usize,
+    pub max_batch_bytes: usize,
+    /// Length of `GET /render` URL, including the encoded code
+    pub max_url_length: usize,
+    pub max_lines: usize,
+    /// In characters
+    pub max_line_length: usize,
+    /// Width multiplied by height of raster output
+    pub max_pixels: usize,
+    /// Wall-clock budget of a render, from parsing to encoding
+    pub render_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultsConfig {
+    pub theme: String,
+    pub format: String,
+    pub picture_width: usize,
+    pub font_aspect_ratio: f32,
+    pub tab_width: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssetsConfig {
+    pub stylesheets: PathBuf,
+    /// Directory scanned for font files at startup
+    pub fonts: PathBuf,
+    /// Default family, overridden with the `font` parameter
+    pub font_family: String,
+    /// Families for characters missing from the requested one, e.g. CJK
+    /// and emoji fonts
+    pub font_fallbacks: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatsConfig {
+    pub enabled: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// Number of images kept in memory
+    pub capacity: usize,
+    /// Directory to persist rendered images to
+    pub dir: Option<PathBuf>,
+    /// Total size of the files in `dir`, least recently used ones are removed
+    /// past it. 0 is unlimited
+    pub max_dir_bytes: u64,
+    /// `max-age` of the `Cache-Control` header, in seconds
+    pub max_age: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnippetsConfig {
+    /// Anyone can save snippets once enabled, so it's off by default
+    pub enabled: bool,
+    /// One of `memory`, `dir` or `sqlite`
+    pub store: String,
+    /// Directory for `dir` store or database file for `sqlite`
+    pub path: PathBuf,
+    /// Size of the snippets kept by the `memory` store, least recently used
+    /// ones are removed past it. 0 is unlimited
+    pub max_memory_bytes: usize,
+    /// Lifetime of snippets saved without `ttl`, in seconds. 0 is forever
+    pub default_ttl: u64,
+    /// Upper bound for `ttl`, 0 for none
+    pub max_ttl: u64,
+    /// How often expired snippets are removed, in seconds
+    pub purge_interval: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter in `RUST_LOG` syntax, which overrides it if set
+    pub level: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            batch_workers: 4,
+            max_batch_items: 1000,
+            max_body_bytes: 1 << 20,
+            max_batch_bytes: 16 << 20,
+            max_url_length: 8 << 10,
+            max_lines: 5000,
+            max_line_length: 1000,
+            max_pixels: 64 << 20,
+            render_timeout_ms: 5000,
+        }
+    }
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        Self {
+            theme: "ayu-vim".to_string(),
+            format: "png".to_string(),
+            picture_width: 512 << 1,
+            font_aspect_ratio: 3. / 5.,
+            tab_width: 4,
+        }
+    }
+}
+
+impl Default for AssetsConfig {
+    fn default() -> Self {
+        Self {
+            stylesheets: PathBuf::from("assets/stylesheets"),
+            fonts: PathBuf::from("assets/fonts"),
+            font_family: "JetBrains Mono".to_string(),
+            font_fallbacks: Vec::new(),
+        }
+    }
+}
+
+impl Default for FormatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: vec![
+                "png".to_string(),
+                "jpeg".to_string(),
+                "webp".to_string(),
+                "svg".to_string(),
+                "pdf".to_string(),
+                "html".to_string(),
+                "ansi".to_string(),
+                "latex".to_string(),
+                "rtf".to_string(),
+                "json".to_string(),
+                "lsp-semantic-tokens".to_string(),
+            ],
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            capacity: 512,
+            dir: None,
+            max_dir_bytes: 1 << 30,
+            max_age: 86400,
+        }
+    }
+}
+
+impl Default for SnippetsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            store: "memory".to_string(),
+            path: PathBuf::from("snippets"),
+            max_memory_bytes: 64 << 20,
+            default_ttl: 0,
+            max_ttl: 0,
+            purge_interval: 600,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Read(path, err) => {
+                write!(f, "cannot read config {}: {}", path.display(), err)
+            }
+            Self::Parse(source, err) => {
+                write!(f, "invalid config in {}: {}", source, err)
+            }
+            Self::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parse an environment variable value into the type of the `default`
+/// value of its key. Lists are comma-separated, strings are taken as is and
+/// anything else is parsed as a TOML value
+pub(crate) fn parse_env_value(
+    raw: &str,
+    default: &serde_json::Value,
+) -> toml::Value {
+    use serde_json::Value;
+    match default {
+        Value::Array(_) if !raw.trim_start().starts_with('[') => {
+            let items = raw
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.to_string()))
+                .collect();
+            toml::Value::Array(items)
+        }
+        Value::String(_) | Value::Null => toml::Value::String(raw.to_string()),
+        _ => toml::from_str::<toml::value::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(raw.to_string())),
+    }
+}
+
+fn from_table(table: &toml::value::Table) -> Result<Config, toml::de::Error> {
+    toml::Value::Table(table.clone()).try_into()
+}
+
+/// Apply `HIGHLIGHT_*` variables, given as name and value, to the config
+/// `table`. Each one is checked as it's applied, so that an invalid value
+/// is reported along with its variable. Returns warnings about variables
+/// that aren't config keys
+pub(crate) fn apply_env_overrides(
+    table: &mut toml::value::Table,
+    overrides: Vec<(String, String)>,
+) -> Result<Vec<String>, Error> {
+    // Keys and their types are taken from the defaults, so that other
+    // variables sharing the prefix don't get in the way
+    let defaults =
+        serde_json::to_value(Config::default()).expect("Config serializes");
+    let mut warnings = Vec::new();
+    for (name, raw) in overrides {
+        let key = name[ENV_PREFIX.len()..].to_lowercase();
+        let known = key.find('_').and_then(|idx| {
+            let (section, key) = (&key[..idx], &key[idx + 1..]);
+            let default = defaults.get(section)?.get(key)?;
+            Some((section, key, default))
+        });
+        let (section, key, default) = match known {
+            Some(known) => known,
+            None => {
+                warnings.push(format!(
+                    "Ignoring {}, which is not a config key",
+                    name
+                ));
+                continue;
+            }
+        };
+        let section = table
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+        match section {
+            toml::Value::Table(section) => {
+                section.insert(key.to_string(), parse_env_value(&raw, default));
+            }
+            _ => {
+                return Err(Error::Invalid(format!(
+                    "{} does not refer to a config section",
+                    name
+                )));
+            }
+        }
+        from_table(table).map_err(|e| Error::Parse(name, e))?;
+    }
+    Ok(warnings)
+}
+
+impl Config {
+    /// Load config from `HIGHLIGHT_CONFIG` or `highlight.toml` and apply
+    /// environment overrides. `PORT` is accepted for backwards compatibility
+    pub fn load() -> Result<Self, Error> {
+        let (path, required) = match env::var_os(CONFIG_PATH_VAR) {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+        let raw = match fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(err) if required || err.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::Read(path, err));
+            }
+            Err(_) => String::new(),
+        };
+        let source = path.display().to_string();
+        let mut table: toml::value::Table = toml::from_str(&raw)
+            .map_err(|e| Error::Parse(source.clone(), e))?;
+        // Checked before the overrides, so that they aren't blamed for it
+        from_table(&table).map_err(|e| Error::Parse(source.clone(), e))?;
+
+        let mut overrides: Vec<_> = env::vars()
+            .filter(|(name, _)| {
+                name.starts_with(ENV_PREFIX) && name != CONFIG_PATH_VAR
+            })
+            .collect();
+        if let Ok(port) = env::var("PORT") {
+            overrides.insert(0, (format!("{}SERVER_PORT", ENV_PREFIX), port));
+        }
+        let warnings = apply_env_overrides(&mut table, overrides)?;
+
+        let mut config =
+            from_table(&table).map_err(|e| Error::Parse(source, e))?;
+        config.validate()?;
+        config.warnings = warnings;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::Invalid(msg));
+        if IpAddr::from_str(&self.server.bind).is_err() {
+            return invalid(format!(
+                "server.bind: `{}` is not an IP address",
+                self.server.bind
+            ));
+        }
+        if self.limits.batch_workers == 0 {
+            return invalid("limits.batch_workers must be positive".into());
+        }
+        for name in self.formats.enabled.iter() {
+            if OutputFormat::parse(name).is_none() {
+                return invalid(format!("formats.enabled: unknown `{}`", name));
+            }
+        }
+        if !self.is_format_enabled(&self.defaults.format) {
+            return invalid(format!(
+                "defaults.format: `{}` is not an enabled format",
+                self.defaults.format
+            ));
+        }
+        if self.defaults.picture_width == 0 {
+            return invalid("defaults.picture_width must be positive".into());
+        }
+        let ratio = self.defaults.font_aspect_ratio;
+        if ratio.is_nan() || ratio <= 0. {
+            return invalid(
+                "defaults.font_aspect_ratio must be positive".into(),
+            );
+        }
+        if self.defaults.tab_width == 0 {
+            return invalid("defaults.tab_width must be positive".into());
+        }
+        if !self.assets.stylesheets.is_dir() {
+            return invalid(format!(
+                "assets.stylesheets: {} is not a directory",
+                self.assets.stylesheets.display()
+            ));
+        }
+        if !self.assets.fonts.is_dir() {
+            return invalid(format!(
+                "assets.fonts: {} is not a directory",
+                self.assets.fonts.display()
+            ));
+        }
+        if self.cache.enabled && self.cache.capacity == 0 {
+            return invalid("cache.capacity must be positive".into());
+        }
+        if !["memory", "dir", "sqlite"].contains(&self.snippets.store.as_str())
+        {
+            return invalid(format!(
+                "snippets.store: unknown `{}`",
+                self.snippets.store
+            ));
+        }
+        if self.snippets.enabled && self.snippets.purge_interval == 0 {
+            return invalid("snippets.purge_interval must be positive".into());
+        }
+        for directive in self.log.level.split(',') {
+            let level = directive.rsplit('=').next().unwrap_or_default();
+            if log::LevelFilter::from_str(level.trim()).is_err() {
+                return invalid(format!("log.level: invalid `{}`", directive));
+            }
+        }
+        Ok(())
+    }
+
+    /// Initialize `env_logger` with `log.level`, unless `RUST_LOG` is set
+    pub fn init_logger(&self) {
+        let env = env_logger::Env::default()
+            .default_filter_or(self.log.level.as_str());
+        env_logger::Builder::from_env(env).init();
+        for warning in &self.warnings {
+            log::warn!("{}", warning);
+        }
+    }
+
+    pub fn bind_address(&self) -> SocketAddr {
+        let ip = IpAddr::from_str(&self.server.bind).expect("Validated");
+        SocketAddr::new(ip, self.server.port)
+    }
+
+    pub fn stylesheets_dir(&self) -> &Path {
+        &self.assets.stylesheets
+    }
+
+    pub fn is_format_enabled(&self, name: &str) -> bool {
+        self.formats.enabled.iter().any(|format| format == name)
+    }
+}
+
+/// Set the global config. Must be called before any rendering happens
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        log::warn!("Config is already initialized");
+    }
+}
+
+/// Get the global config, falling back to defaults if `init` wasn't called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
@@ -0,0 +1,334 @@
+//! Fonts from the `assets.fonts` directory. Every face found there is
+//! registered under its family, so bold and italic text is drawn with the
+//! matching face when the family ships one. Characters missing from the
+//! requested family are looked up in `assets.font_fallbacks`, in order.
+//!
+//! OpenType features are passed to browsers with CSS. usvg, which draws PNG
+//! output and outlines text, shapes it with the default features of the
+//! font, so the only one it honors is turning ligatures off, done by
+//! positioning every glyph on its own.
+
+use crate::config::{self, Config};
+use crate::subset;
+use crate::utils::USVG_TREE_OPTIONS;
+use std::collections::{BTreeMap, BTreeSet};
+use usvg::fontdb;
+
+fn face_name(face: &fontdb::FaceInfo) -> &'static str {
+    face_name_for(
+        face.weight >= fontdb::Weight::SEMIBOLD,
+        face.style != fontdb::Style::Normal,
+    )
+}
+
+fn face_name_for(bold: bool, italic: bool) -> &'static str {
+    match (bold, italic) {
+        (false, false) => "regular",
+        (true, false) => "bold",
+        (false, true) => "italic",
+        (true, true) => "bold italic",
+    }
+}
+
+/// Scan `assets.fonts`, logging the faces found for every family
+pub(crate) fn load_database(config: &Config) -> fontdb::Database {
+    let mut fontdb = fontdb::Database::new();
+    fontdb.load_fonts_dir(&config.assets.fonts);
+    fontdb.set_monospace_family(config.assets.font_family.as_str());
+    let mut families: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for face in fontdb.faces() {
+        families
+            .entry(face.family.as_str())
+            .or_default()
+            .push(face_name(face));
+    }
+    for (family, faces) in families {
+        log::info!("Font `{}`: {}", family, faces.join(", "));
+    }
+    fontdb
+}
+
+pub fn has_family(family: &str) -> bool {
+    USVG_TREE_OPTIONS
+        .fontdb
+        .faces()
+        .iter()
+        .any(|face| face.family == family)
+}
+
+/// Load fonts and check that the configured families are there, so that a
+/// missing font is reported at startup rather than on the first render
+pub fn init() -> Result<(), config::Error> {
+    let config = config::get();
+    let family = &config.assets.font_family;
+    if !has_family(family) {
+        return Err(config::Error::Invalid(format!(
+            "assets.font_family: no font `{}` in {}",
+            family,
+            config.assets.fonts.display()
+        )));
+    }
+    for fallback in &config.assets.font_fallbacks {
+        if !has_family(fallback) {
+            log::warn!(
+                "Fallback font `{}` not found in {}",
+                fallback,
+                config.assets.fonts.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Quote a font family name as a CSS string. `<` and `&` are escaped too,
+/// since the CSS goes into `<style>` elements of SVG
+pub fn css_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        match c {
+            '\'' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted += "\\a ",
+            '<' => quoted += "\\3c ",
+            '&' => quoted += "\\26 ",
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Value of the CSS `font-family` property: `family`, then the fallbacks
+pub fn font_family_list(family: &str) -> String {
+    let fallbacks = &config::get().assets.font_fallbacks;
+    let mut list: Vec<_> = std::iter::once(family)
+        .chain(fallbacks.iter().map(String::as_str))
+        .map(css_string)
+        .collect();
+    list.push("monospace".to_string());
+    list.join(", ")
+}
+
+/// Font data for a data URI along with its CSS format
+pub(crate) fn embeddable_font(
+    data: &[u8],
+    index: u32,
+    chars: &BTreeSet<char>,
+) -> Option<(Vec<u8>, &'static str)> {
+    if let Some(font) = subset::subset(data, index, chars) {
+        return Some((font, "truetype"));
+    }
+    // CFF outlines are embedded whole
+    if data.starts_with(b"OTTO") {
+        Some((data.to_vec(), "opentype"))
+    } else {
+        None
+    }
+}
+
+/// `@font-face` rules embedding the faces of `family`, subset to `chars`.
+/// Fallback families are embedded too if they draw any of the characters
+/// that `family` lacks
+pub fn embedded_font_faces(family: &str, chars: &BTreeSet<char>) -> String {
+    let fallbacks = &config::get().assets.font_fallbacks;
+    font_face_rules(
+        &USVG_TREE_OPTIONS.fontdb,
+        std::iter::once(family).chain(fallbacks.iter().map(String::as_str)),
+        chars,
+    )
+}
+
+/// Characters of `chars` drawn by each of `families` in `fontdb` that the
+/// ones before it lack. Families that add none are left out
+pub(crate) fn family_coverage<'a>(
+    fontdb: &fontdb::Database,
+    families: impl IntoIterator<Item = &'a str>,
+    chars: &BTreeSet<char>,
+) -> Vec<(&'a str, BTreeSet<char>)> {
+    let mut missing = chars.clone();
+    let mut coverage = Vec::new();
+    for name in families {
+        if missing.is_empty() {
+            break;
+        }
+        // Judged by the first face, all faces of a family usually match
+        let covered: BTreeSet<char> = fontdb
+            .faces()
+            .iter()
+            .find(|face| face.family == name)
+            .and_then(|face| {
+                fontdb.with_face_data(face.id, |data, index| {
+                    let face =
+                        ttf_parser::Face::from_slice(data, index).ok()?;
+                    Some(
+                        missing
+                            .iter()
+                            .copied()
+                            .filter(|&c| face.glyph_index(c).is_some())
+                            .collect(),
+                    )
+                })
+            })
+            .flatten()
+            .unwrap_or_default();
+        if covered.is_empty() {
+            continue;
+        }
+        missing = &missing - &covered;
+        coverage.push((name, covered));
+    }
+    coverage
+}
+
+/// `@font-face` rules for the faces of `families` in `fontdb`, each one only
+/// if it draws characters that the ones before it lack
+pub(crate) fn font_face_rules<'a>(
+    fontdb: &fontdb::Database,
+    families: impl IntoIterator<Item = &'a str>,
+    chars: &BTreeSet<char>,
+) -> String {
+    let mut css = String::new();
+    for (name, covered) in family_coverage(fontdb, families, chars) {
+        let faces = fontdb.faces().iter().filter(|face| face.family == name);
+        for face in faces {
+            let font = fontdb
+                .with_face_data(face.id, |data, index| {
+                    embeddable_font(data, index, &covered)
+                })
+                .flatten();
+            let (font, format) = match font {
+                Some(font) => font,
+                None => {
+                    log::warn!("Cannot embed font `{}`", face.post_script_name);
+                    continue;
+                }
+            };
+            let style = match face.style {
+                fontdb::Style::Normal => "normal",
+                fontdb::Style::Italic => "italic",
+                fontdb::Style::Oblique => "oblique",
+            };
+            css += &format!(
+                "@font-face{{font-family:{};font-weight:{};font-style:{};\
+                src:url(data:font/{};base64,{}) format('{}');}}\n",
+                css_string(name),
+                face.weight.0,
+                style,
+                if format == "truetype" { "ttf" } else { "otf" },
+                base64::encode(&font),
+                format,
+            );
+        }
+    }
+    css
+}
+
+/// Face of `family` with the given weight and style, subset to `chars` for
+/// embedding, or whole if it has CFF outlines. `None` if the family has no
+/// such face
+pub fn subset_face(
+    family: &str,
+    bold: bool,
+    italic: bool,
+    chars: &BTreeSet<char>,
+) -> Option<Vec<u8>> {
+    let fontdb = &USVG_TREE_OPTIONS.fontdb;
+    let id = fontdb.query(&fontdb::Query {
+        families: &[fontdb::Family::Name(family)],
+        weight: if bold {
+            fontdb::Weight::BOLD
+        } else {
+            fontdb::Weight::NORMAL
+        },
+        stretch: fontdb::Stretch::Normal,
+        style: if italic {
+            fontdb::Style::Italic
+        } else {
+            fontdb::Style::Normal
+        },
+    })?;
+    // The query falls back to the closest face, e.g. regular for bold
+    let face = fontdb.face(id)?;
+    if face_name(face) != face_name_for(bold, italic) {
+        return None;
+    }
+    fontdb
+        .with_face_data(id, |data, index| embeddable_font(data, index, chars))
+        .flatten()
+        .map(|(font, _)| font)
+}
+
+/// OpenType features requested with `ligatures` and `font_features`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FontFeatures {
+    pub ligatures: bool,
+    /// Feature tags with their values, e.g. `ss01` 1 or `calt` 0
+    pub settings: Vec<(String, u32)>,
+}
+
+impl Default for FontFeatures {
+    fn default() -> Self {
+        Self {
+            ligatures: true,
+            settings: Vec::new(),
+        }
+    }
+}
+
+impl FontFeatures {
+    /// Parse a list like `ss01,zero,-calt,cv01=2`, where `-` turns a
+    /// feature off
+    pub fn parse_settings(value: &str) -> Option<Vec<(String, u32)>> {
+        let mut settings = Vec::new();
+        for part in value.split(',').map(str::trim) {
+            if part.is_empty() {
+                continue;
+            }
+            let (tag, value) = match part.strip_prefix('-') {
+                Some(tag) => (tag, 0),
+                None => {
+                    let mut parts = part.splitn(2, '=');
+                    let tag = parts.next()?;
+                    match parts.next() {
+                        Some(value) => (tag, value.parse().ok()?),
+                        None => (tag, 1),
+                    }
+                }
+            };
+            if tag.len() != 4 || !tag.bytes().all(|b| b.is_ascii_alphanumeric())
+            {
+                return None;
+            }
+            settings.push((tag.to_string(), value));
+        }
+        Some(settings)
+    }
+
+    /// Whether glyphs must not be joined into ligatures
+    pub fn disables_ligatures(&self) -> bool {
+        !self.ligatures
+            || self.settings.iter().any(|(tag, value)| {
+                *value == 0 && ["liga", "clig", "calt"].contains(&tag.as_str())
+            })
+    }
+
+    /// CSS declarations, empty if the font defaults are kept
+    pub fn css(&self) -> String {
+        let mut css = String::new();
+        if !self.ligatures {
+            css += "font-variant-ligatures:none;";
+        }
+        if !self.settings.is_empty() {
+            let settings: Vec<_> = self
+                .settings
+                .iter()
+                .map(|(tag, value)| format!("\"{}\" {}", tag, value))
+                .collect();
+            css += &format!("font-feature-settings:{};", settings.join(","));
+        }
+        css
+    }
+}
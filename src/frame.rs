@@ -0,0 +1,200 @@
+//! Presentation framing around the code: a window with an optional title
+//! bar, rounded corners and a drop shadow, placed on an outer background.
+
+use htmlescape::{encode_attribute, encode_minimal};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowStyle {
+    None,
+    MacOs,
+    Windows,
+}
+
+impl WindowStyle {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "macos" => Some(Self::MacOs),
+            "windows" => Some(Self::Windows),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub window: WindowStyle,
+    /// Text in the middle of the title bar, usually a file name
+    pub title: Option<String>,
+    /// Space between the window and the edges of the picture, in pixels
+    pub margin: usize,
+    pub radius: usize,
+    pub shadow: bool,
+    /// Color around the window, transparent if `None`
+    pub background: Option<String>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self {
+            window: WindowStyle::None,
+            title: None,
+            margin: 0,
+            radius: 0,
+            shadow: false,
+            background: None,
+        }
+    }
+}
+
+const MACOS_BUTTONS: [&str; 3] = ["#FF5F56", "#FFBD2E", "#27C93F"];
+/// Blur radius plus offset of the shadow, about 3 standard deviations
+const SHADOW_SIZE: usize = 12 * 3 + 8;
+
+impl Frame {
+    pub fn has_title_bar(&self) -> bool {
+        self.window != WindowStyle::None || self.title.is_some()
+    }
+
+    /// Space around the window, `margin` but large enough to fit the shadow
+    pub fn outer_margin(&self) -> usize {
+        if self.shadow {
+            self.margin.max(SHADOW_SIZE)
+        } else {
+            self.margin
+        }
+    }
+
+    /// Height of the title bar, 0 if there's none
+    pub fn title_bar_height(&self, font_size: usize) -> usize {
+        if self.has_title_bar() {
+            font_size * 2
+        } else {
+            0
+        }
+    }
+
+    /// Definitions and outer background, going before the window
+    pub fn defs(&self, width: usize, height: usize) -> String {
+        let mut svg = String::new();
+        if self.shadow || self.radius > 0 {
+            svg += "<defs>";
+            if self.shadow {
+                // Sized in pixels, a percentage of a small window would cut
+                // the blur off
+                svg += &format!(
+                    r#"<filter id="shadow" filterUnits="userSpaceOnUse" x="-{s}" y="-{s}" width="{}" height="{}">"#,
+                    width + 2 * SHADOW_SIZE,
+                    height + 2 * SHADOW_SIZE,
+                    s = SHADOW_SIZE,
+                );
+                svg += concat!(
+                    r#"<feGaussianBlur in="SourceAlpha" stdDeviation="12" />"#,
+                    r#"<feOffset dy="8" result="blur" />"#,
+                    r##"<feFlood flood-color="#000000" flood-opacity="0.45" />"##,
+                    r#"<feComposite in2="blur" operator="in" />"#,
+                    r#"<feMerge><feMergeNode /><feMergeNode in="SourceGraphic" /></feMerge>"#,
+                    "</filter>",
+                );
+            }
+            if self.radius > 0 {
+                svg += &format!(
+                    r#"<clipPath id="window"><rect width="{}" height="{}" rx="{}" /></clipPath>"#,
+                    width, height, self.radius,
+                );
+            }
+            svg += "</defs>\n";
+        }
+        if let Some(color) = &self.background {
+            svg += &format!(
+                r#"<rect width="100%" height="100%" fill="{}" />"#,
+                encode_attribute(color),
+            );
+            svg.push('\n');
+        }
+        svg
+    }
+
+    /// The window itself, with its size in `width` and `height`
+    pub fn window(&self, width: usize, height: usize) -> String {
+        format!(
+            r#"<rect width="{}" height="{}" rx="{}" class="background"{} />"#,
+            width,
+            height,
+            self.radius,
+            if self.shadow {
+                r#" filter="url(#shadow)""#
+            } else {
+                ""
+            },
+        ) + "\n"
+    }
+
+    pub fn title_bar(&self, width: usize, font_size: usize) -> String {
+        let height = self.title_bar_height(font_size);
+        if height == 0 {
+            return String::new();
+        }
+        let center = height as f32 / 2.;
+        let mut svg = format!(
+            r#"<rect width="{}" height="{}" class="title-bar" />"#,
+            width, height
+        );
+        match self.window {
+            WindowStyle::MacOs => {
+                let radius = font_size as f32 * 0.3;
+                for (idx, color) in MACOS_BUTTONS.iter().enumerate() {
+                    svg += &format!(
+                        r#"<circle cx="{}" cy="{}" r="{}" fill="{}" />"#,
+                        center + idx as f32 * radius * 3.3,
+                        center,
+                        radius,
+                        color,
+                    );
+                }
+            }
+            WindowStyle::Windows => {
+                // Minimize, maximize and close, right to left
+                let size = font_size as f32 * 0.4;
+                let step = font_size as f32 * 1.6;
+                let x = width as f32 - step;
+                let top = center - size / 2.;
+                svg += r#"<g class="title-buttons">"#;
+                svg += &format!(
+                    r#"<path d="M{} {}l{} {}M{} {}l{} {}" />"#,
+                    x,
+                    top,
+                    size,
+                    size,
+                    x + size,
+                    top,
+                    -size,
+                    size,
+                );
+                svg += &format!(
+                    r#"<rect x="{}" y="{}" width="{s}" height="{s}" fill="none" />"#,
+                    x - step,
+                    top,
+                    s = size,
+                );
+                svg += &format!(
+                    r#"<path d="M{} {}h{}" />"#,
+                    x - 2. * step,
+                    center,
+                    size,
+                );
+                svg += "</g>";
+            }
+            WindowStyle::None => {}
+        }
+        if let Some(title) = &self.title {
+            svg += &format!(
+                r#"<text x="{}" y="{}" class="window-title" text-anchor="middle">{}</text>"#,
+                width as f32 / 2.,
+                center + font_size as f32 * 0.35,
+                encode_minimal(title),
+            );
+        }
+        svg + "\n"
+    }
+}
@@ -0,0 +1,149 @@
+use crate::line_ranges::Row;
+use crate::render::RenderOptions;
+use crate::traversal::{self, Visitor};
+use crate::utils::TabExpander;
+use htmlescape::encode_minimal;
+use std::ops::Range;
+use tree_sitter_highlight::{Error, Highlight, HighlightEvent};
+
+/// Lines of markup. Spans open at the end of a line are closed there and
+/// reopened on the next one, so that every line stands on its own
+struct HtmlLines<'a> {
+    source: &'a str,
+    attributes: &'a [String],
+    hl_stack: Vec<Highlight>,
+    tabs: TabExpander,
+    lines: Vec<String>,
+    line: String,
+}
+
+impl HtmlLines<'_> {
+    fn open_span(&mut self, highlight: Highlight) {
+        self.line += "<span ";
+        self.line += &self.attributes[highlight.0];
+        self.line.push('>');
+    }
+}
+
+impl Visitor for HtmlLines<'_> {
+    fn start_highlight(&mut self, highlight: Highlight) {
+        self.open_span(highlight);
+        self.hl_stack.push(highlight);
+    }
+
+    fn end_highlight(&mut self) {
+        self.hl_stack.pop();
+        self.line += "</span>";
+    }
+
+    fn text(&mut self, range: Range<usize>) {
+        let source = self.source;
+        self.line += &encode_minimal(&self.tabs.expand(&source[range]));
+    }
+
+    fn line_break(&mut self) {
+        self.tabs.new_line();
+        self.line += &"</span>".repeat(self.hl_stack.len());
+        self.lines.push(std::mem::take(&mut self.line));
+        for highlight in self.hl_stack.clone() {
+            self.open_span(highlight);
+        }
+    }
+}
+
+/// Render highlight events into a standalone HTML document. `attributes`
+/// holds the attribute string of every highlight name
+pub fn render<I>(
+    events: I,
+    source: &str,
+    attributes: &[String],
+    stylesheet: &str,
+    options: &RenderOptions,
+) -> Result<String, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let mut markup = HtmlLines {
+        source,
+        attributes,
+        hl_stack: Vec::new(),
+        tabs: TabExpander::new(options.tab_width),
+        lines: Vec::new(),
+        line: String::new(),
+    };
+    traversal::walk(events, source, &mut markup)?;
+    let mut lines = markup.lines;
+    lines.push(markup.line);
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<style>
+{}
+</style>
+</head>
+<body>
+<pre class="highlight"><code>"#,
+        stylesheet
+    );
+    let rows =
+        options
+            .lines
+            .rows(options.start_line, lines.len(), options.ellipsis);
+    let last_shown = rows.iter().rev().find_map(|row| match row {
+        Row::Line(idx) => Some(*idx),
+        Row::Elided => None,
+    });
+    let number_width = if options.line_numbers {
+        (options.start_line + last_shown.unwrap_or(0))
+            .to_string()
+            .len()
+    } else {
+        0
+    };
+    for row in rows {
+        let idx = match row {
+            Row::Line(idx) => idx,
+            Row::Elided if options.ellipsis => {
+                html += &format!(
+                    r#"<span class="gutter elided">{:>width$}</span>"#,
+                    "…",
+                    width = number_width,
+                );
+                html.push('\n');
+                continue;
+            }
+            Row::Elided => {
+                html += "<span class=\"separator\"></span>\n";
+                continue;
+            }
+        };
+        let line_number = options.start_line + idx;
+        let is_highlighted = options.highlight_lines.contains(line_number);
+        let mut classes = Vec::new();
+        if is_highlighted {
+            classes.push("line-highlight");
+        }
+        if options.dim && !is_highlighted {
+            classes.push("dimmed");
+        }
+        if !classes.is_empty() {
+            html += &format!(r#"<span class="{}">"#, classes.join(" "));
+        }
+        if options.line_numbers {
+            html += &format!(
+                r#"<span class="gutter">{:>width$} </span>"#,
+                line_number,
+                width = number_width,
+            );
+        }
+        html += &lines[idx];
+        if !classes.is_empty() {
+            html += "</span>";
+        }
+        html.push('\n');
+    }
+    html += "</code></pre>\n</body>\n</html>\n";
+    Ok(html)
+}
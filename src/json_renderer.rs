@@ -0,0 +1,99 @@
+//! Highlighted tokens as JSON, for clients doing their own rendering.
+//! Ranges refer to the source as sent, tabs aren't expanded
+
+use crate::render::RenderOptions;
+use crate::styled_text::token_lines;
+use crate::theme::{Style, Theme};
+use serde::Serialize;
+use tree_sitter_highlight::{Error, HighlightEvent};
+
+#[derive(Serialize)]
+struct Document<'a> {
+    background: Option<&'a str>,
+    foreground: Option<&'a str>,
+    lines: Vec<Line<'a>>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    number: usize,
+    highlighted: bool,
+    tokens: Vec<Token<'a>>,
+}
+
+#[derive(Serialize)]
+struct Token<'a> {
+    text: &'a str,
+    /// Start and end offsets in bytes
+    bytes: [usize; 2],
+    /// Start and end offsets in characters
+    chars: [usize; 2],
+    /// Capture names of nested highlights, outermost first
+    scopes: Vec<&'a str>,
+    style: Style,
+}
+
+/// Render highlight events into JSON lines of tokens. Lines left out by
+/// `lines` are skipped
+pub fn render<I>(
+    events: I,
+    source: &str,
+    names: &[String],
+    theme: &Theme,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let styles: Vec<_> =
+        names.iter().map(|name| theme.style_for(name)).collect();
+    // Character offset of a byte offset, counted on from the last token
+    let mut counted = (0, 0);
+    let mut char_offset = |byte: usize| {
+        counted.1 += source[counted.0..byte].chars().count();
+        counted.0 = byte;
+        counted.1
+    };
+
+    let mut lines = Vec::new();
+    for (idx, tokens) in token_lines(events, source)?.into_iter().enumerate() {
+        let number = options.start_line + idx;
+        if !options.lines.is_empty() && !options.lines.contains(number) {
+            continue;
+        }
+        let tokens = tokens
+            .into_iter()
+            .map(|token| {
+                let mut style = Style::default();
+                for hl in &token.highlights {
+                    style.merge(&styles[hl.0]);
+                }
+                let start = char_offset(token.range.start);
+                let end = char_offset(token.range.end);
+                Token {
+                    text: &source[token.range.clone()],
+                    bytes: [token.range.start, token.range.end],
+                    chars: [start, end],
+                    scopes: token
+                        .highlights
+                        .iter()
+                        .map(|hl| names[hl.0].as_str())
+                        .collect(),
+                    style,
+                }
+            })
+            .collect();
+        lines.push(Line {
+            number,
+            highlighted: options.highlight_lines.contains(number),
+            tokens,
+        });
+    }
+
+    let document = Document {
+        background: theme.global("background"),
+        foreground: theme.global("foreground"),
+        lines,
+    };
+    Ok(serde_json::to_vec(&document).expect("Tokens serialize to JSON"))
+}
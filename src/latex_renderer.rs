@@ -0,0 +1,157 @@
+//! LaTeX output: a `SaveVerbatim` environment of the fancyvrb package
+//! colored with `\textcolor` of xcolor, preceded by definitions of the
+//! colors and drawn on the background of the theme with `\colorbox`
+
+use crate::line_ranges::Row;
+use crate::metrics::str_width;
+use crate::render::RenderOptions;
+use crate::styled_text::{styled_lines, Palette};
+use crate::theme::Theme;
+use crate::traversal;
+use crate::utils::expand_tabs;
+use tree_sitter_highlight::{Error, HighlightEvent};
+
+/// Escape text for `Verbatim` with `\`, `{` and `}` as command characters
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped += r"\char92{}",
+            '{' => escaped += r"\char123{}",
+            '}' => escaped += r"\char125{}",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Name of `color` in the preamble, defining it on first use
+fn color_name(colors: &mut Vec<[u8; 3]>, color: [u8; 3]) -> String {
+    let idx = match colors.iter().position(|&known| known == color) {
+        Some(idx) => idx,
+        None => {
+            colors.push(color);
+            colors.len() - 1
+        }
+    };
+    format!("hl{}", idx)
+}
+
+/// Render highlight events into a LaTeX snippet, which needs the fancyvrb
+/// and xcolor packages
+pub fn render<I>(
+    events: I,
+    source: &str,
+    names: &[String],
+    theme: &Theme,
+    options: &RenderOptions,
+) -> Result<String, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let lines = styled_lines(events, source, names, theme, options.tab_width)?;
+    let palette = Palette::new(theme);
+    let rows =
+        options
+            .lines
+            .rows(options.start_line, lines.len(), options.ellipsis);
+    let last_shown = rows.iter().rev().find_map(|row| match row {
+        Row::Line(idx) => Some(*idx),
+        Row::Elided => None,
+    });
+    let number_width = if options.line_numbers {
+        (options.start_line + last_shown.unwrap_or(0))
+            .to_string()
+            .len()
+    } else {
+        0
+    };
+
+    let code_width = traversal::lines(source)
+        .map(|line| str_width(&expand_tabs(line, options.tab_width)))
+        .max();
+
+    let mut colors = Vec::new();
+    let mut body = String::with_capacity(source.len() * 2);
+    for row in rows {
+        let idx = match row {
+            Row::Line(idx) => idx,
+            Row::Elided => {
+                let gap = if options.ellipsis {
+                    format!(
+                        "{:>width$}\\ldots{{}}",
+                        "",
+                        width = number_width.saturating_sub(1),
+                    )
+                } else {
+                    // pdfLaTeX has no glyphs for box drawing characters
+                    let width = number_width + 1 + code_width.unwrap_or(0);
+                    "-".repeat(width)
+                };
+                body += &format!(
+                    "\\textcolor{{{}}}{{{}}}\n",
+                    color_name(&mut colors, palette.gutter),
+                    gap,
+                );
+                continue;
+            }
+        };
+        let line_number = options.start_line + idx;
+        let is_dimmed =
+            options.dim && !options.highlight_lines.contains(line_number);
+        let paint = |color: [u8; 3]| {
+            if is_dimmed {
+                palette.fade(color)
+            } else {
+                color
+            }
+        };
+        if options.line_numbers {
+            body += &format!(
+                "\\textcolor{{{}}}{{{:>width$} }}",
+                color_name(&mut colors, paint(palette.gutter)),
+                line_number,
+                width = number_width,
+            );
+        }
+        for (text, style) in &lines[idx] {
+            let mut text = escape(text);
+            if style.bold {
+                text = format!("\\textbf{{{}}}", text);
+            }
+            if style.italic {
+                text = format!("\\textit{{{}}}", text);
+            }
+            if style.underline {
+                text = format!("\\underline{{{}}}", text);
+            }
+            body += &format!(
+                "\\textcolor{{{}}}{{{}}}",
+                color_name(&mut colors, paint(palette.color(style))),
+                text,
+            );
+        }
+        body.push('\n');
+    }
+
+    let mut latex =
+        "% Needs \\usepackage{fancyvrb} and \\usepackage{xcolor}\n".to_string();
+    let background =
+        std::iter::once(("hlbackground".to_string(), palette.background));
+    let named_colors = colors
+        .iter()
+        .enumerate()
+        .map(|(idx, &color)| (format!("hl{}", idx), color));
+    for (name, [r, g, b]) in background.chain(named_colors) {
+        latex += &format!(
+            "\\definecolor{{{}}}{{HTML}}{{{:02X}{:02X}{:02X}}}\n",
+            name, r, g, b
+        );
+    }
+    // Saved first, since verbatim text can't go into `\colorbox` directly
+    latex += "\\begin{SaveVerbatim}[commandchars=\\\\\\{\\}]{hlcode}\n";
+    latex += &body;
+    latex += "\\end{SaveVerbatim}\n";
+    latex += "\\colorbox{hlbackground}{\\BUseVerbatim{hlcode}}\n";
+    Ok(latex)
+}
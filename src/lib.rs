@@ -0,0 +1,35 @@
+//! Syntax highlighting of source code into images, powered by tree-sitter.
+//!
+//! The [`Renderer`] builder is the main entry point; the HTTP server and the
+//! `ts-highlight` command-line tool are thin frontends on top of it.
+
+pub mod ansi_renderer;
+pub mod batch;
+pub mod cache;
+pub mod config;
+pub mod fonts;
+pub mod frame;
+pub mod html_renderer;
+pub mod json_renderer;
+pub mod latex_renderer;
+pub mod line_ranges;
+pub mod lsp_renderer;
+pub mod metrics;
+pub mod pdf_renderer;
+pub mod raster;
+pub mod render;
+pub mod rtf_renderer;
+pub mod snippets;
+pub mod styled_text;
+pub mod stylesheet;
+pub mod sublime_colors;
+pub mod subset;
+pub mod svg_renderer;
+#[cfg(test)]
+mod tests;
+pub mod theme;
+pub mod traversal;
+pub mod url_code;
+pub mod utils;
+
+pub use render::{Error, OutputFormat, RenderOptions, Renderer};
@@ -0,0 +1,97 @@
+use std::{fmt, ops::RangeInclusive};
+
+/// Set of line numbers written as `3,7-9`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineRanges(Vec<RangeInclusive<usize>>);
+
+/// A row of output: either a source line, by its index, or a marker in
+/// place of lines that were left out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Row {
+    Line(usize),
+    Elided,
+}
+
+impl LineRanges {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut ranges = Vec::new();
+        for part in value.split(',').map(str::trim) {
+            if part.is_empty() {
+                continue;
+            }
+            let mut bounds = part.splitn(2, '-');
+            let start = bounds.next()?.trim().parse().ok()?;
+            let end = match bounds.next() {
+                Some(end) => end.trim().parse().ok()?,
+                None => start,
+            };
+            if start > end {
+                return None;
+            }
+            ranges.push(start..=end);
+        }
+        Some(Self(ranges))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, line: usize) -> bool {
+        self.0.iter().any(|range| range.contains(&line))
+    }
+
+    /// Lay out a source of `line_count` lines numbered from `start_line`
+    /// when only lines in the set are shown; an empty set shows every line.
+    /// Gaps between shown lines are always marked, while lines left out
+    /// before the first or after the last shown one are only marked with
+    /// `mark_ends`
+    pub fn rows(
+        &self,
+        start_line: usize,
+        line_count: usize,
+        mark_ends: bool,
+    ) -> Vec<Row> {
+        if self.is_empty() {
+            return (0..line_count).map(Row::Line).collect();
+        }
+        let mut rows = Vec::new();
+        let mut last_shown = None;
+        for idx in 0..line_count {
+            if !self.contains(start_line + idx) {
+                continue;
+            }
+            let has_gap = match last_shown {
+                Some(last) => idx > last + 1,
+                None => idx > 0 && mark_ends,
+            };
+            if has_gap {
+                rows.push(Row::Elided);
+            }
+            rows.push(Row::Line(idx));
+            last_shown = Some(idx);
+        }
+        let hidden_after =
+            last_shown.map_or(line_count > 0, |last| last + 1 < line_count);
+        if mark_ends && hidden_after {
+            rows.push(Row::Elided);
+        }
+        rows
+    }
+}
+
+impl fmt::Display for LineRanges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, range) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ",")?;
+            }
+            if range.start() == range.end() {
+                write!(f, "{}", range.start())?;
+            } else {
+                write!(f, "{}-{}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
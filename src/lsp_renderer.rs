@@ -0,0 +1,86 @@
+//! Semantic tokens in the encoding of the Language Server Protocol, so that
+//! editors can use highlighting of languages without a language server.
+//! Capture names are the token types of the legend, there are no modifiers
+
+use crate::styled_text::token_lines;
+use serde::Serialize;
+use tree_sitter_highlight::{Error, HighlightEvent};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Legend<'a> {
+    token_types: &'a [String],
+    token_modifiers: &'a [String],
+}
+
+#[derive(Serialize)]
+struct SemanticTokens<'a> {
+    legend: Legend<'a>,
+    /// Groups of 5 integers per token: line delta, start delta, length,
+    /// token type and modifiers
+    data: Vec<u32>,
+}
+
+/// Length of `text` in UTF-16 code units, the unit of LSP columns
+fn utf16_len(text: &str) -> u32 {
+    text.encode_utf16().count() as u32
+}
+
+/// Render highlight events into the legend and the delta-encoded data of
+/// `SemanticTokens`. Every line is included, text only gets the type of the
+/// innermost highlight, and text outside of highlights is left out
+pub fn render<I>(
+    events: I,
+    source: &str,
+    names: &[String],
+) -> Result<Vec<u8>, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let mut data = Vec::new();
+    let (mut prev_line, mut prev_start) = (0, 0);
+    let mut line_start = 0;
+    for (line_idx, (tokens, line)) in token_lines(events, source)?
+        .into_iter()
+        .zip(source.split('\n'))
+        .enumerate()
+    {
+        let mut column = 0;
+        let mut counted = line_start;
+        for token in tokens {
+            let start = column + utf16_len(&source[counted..token.range.start]);
+            let length = utf16_len(&source[token.range.clone()]);
+            column = start + length;
+            counted = token.range.end;
+            let hl = match token.highlights.last() {
+                Some(hl) => hl,
+                None => continue,
+            };
+            let line_idx = line_idx as u32;
+            let delta_start = if line_idx == prev_line {
+                start - prev_start
+            } else {
+                start
+            };
+            data.extend_from_slice(&[
+                line_idx - prev_line,
+                delta_start,
+                length,
+                hl.0 as u32,
+                0,
+            ]);
+            prev_line = line_idx;
+            prev_start = start;
+        }
+        line_start += line.len() + 1;
+    }
+
+    let tokens = SemanticTokens {
+        legend: Legend {
+            token_types: names,
+            token_modifiers: &[],
+        },
+        data,
+    };
+    Ok(serde_json::to_vec(&tokens).expect("Tokens serialize to JSON"))
+}
@@ -1,101 +1,456 @@
-mod svg_renderer;
-#[cfg(test)]
-mod tests;
-mod utils;
-
-use crate::svg_renderer::SvgRenderer;
+use hyper::body::HttpBody;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{body, http, Body, Method, Request, Response, Server};
-use std::{collections::HashMap, env, fs};
-use tree_sitter_highlight::{Highlight, HighlightConfiguration, Highlighter};
-use utils::{get_language, USVG_TREE_OPTIONS};
+use hyper::{http, Body, Method, Request, Response, Server};
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, process, time::Duration};
+use tree_sitter_highlight_server::batch::{self, Manifest};
+use tree_sitter_highlight_server::config::{self, Config};
+use tree_sitter_highlight_server::snippets::{self, Snippet, SnippetStore};
+use tree_sitter_highlight_server::utils::get_language;
+use tree_sitter_highlight_server::{cache, fonts, url_code};
+use tree_sitter_highlight_server::{Error, RenderOptions, Renderer};
+
+static SNIPPETS: OnceCell<Box<dyn SnippetStore>> = OnceCell::new();
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    env_logger::init();
-    let port = env::var("PORT").unwrap_or(String::from("8080"));
-    let addr = format!("127.0.0.1:{}", port)
-        .parse()
-        .expect("Invalid port value");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    config.init_logger();
+    let addr = config.bind_address();
+    config::init(config);
+    if let Err(err) = fonts::init() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+    init_snippet_store();
     let make_srv =
         make_service_fn(|_| async { Ok::<_, http::Error>(service_fn(serve)) });
     let server = Server::bind(&addr).serve(make_srv);
-    log::info!("Server listening on :{}", port);
+    log::info!("Server listening on {}", addr);
     if let Err(err) = server.await {
         log::error!("{:?}", err);
     }
 }
 
+/// Decode `%XX` escapes and `+` as space
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = iter.clone().take(2).collect::<Vec<_>>();
+                let decoded = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match decoded {
+                    Some(decoded) => {
+                        bytes.push(decoded);
+                        iter.nth(1);
+                    }
+                    None => bytes.push(byte),
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
 fn parse_query_string(query: &str) -> HashMap<String, String> {
     query.split('&').fold(HashMap::new(), |mut acc, cur| {
-        let kv: Vec<_> = cur.splitn(2, '=').collect();
-        acc.insert(kv[0].to_string(), kv[1].to_string());
+        let mut kv = cur.splitn(2, '=');
+        if let Some(key) = kv.next().filter(|key| !key.is_empty()) {
+            let value = kv.next().unwrap_or_default();
+            acc.insert(percent_decode(key), percent_decode(value));
+        }
         acc
     })
 }
 
-async fn highlight(req: Request<Body>) -> Option<Body> {
-    let qs = req.uri().query().map(parse_query_string);
-    let language_name = qs.map(|qs| qs.get("lang").cloned()).flatten()?;
-    let bytes = body::to_bytes(req.into_body())
-        .await
-        .ok()?
-        .iter()
-        .cloned()
-        .collect::<Vec<_>>();
-    let source_code = String::from_utf8(bytes).ok()?;
-
-    let mut hl_cfg = {
-        let cfg = get_language(&language_name)?;
-        HighlightConfiguration::new(
-            cfg.language,
-            &cfg.highlight_query,
-            &cfg.injections_query,
-            &cfg.locals_query,
+fn error_response(status: u16, message: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(message.into())
+        .unwrap()
+}
+
+fn render_error_response(err: Error) -> Response<Body> {
+    log::warn!("{}", err);
+    let status = match err {
+        Error::TooManyLines { .. }
+        | Error::LineTooLong { .. }
+        | Error::PictureTooLarge { .. }
+        | Error::Timeout => 422,
+        _ => 400,
+    };
+    error_response(status, err.to_string())
+}
+
+/// Read the whole request body, failing with 413 if it exceeds `limit` bytes
+async fn read_body(
+    mut body: Body,
+    limit: usize,
+) -> Result<Vec<u8>, Response<Body>> {
+    let too_large = || {
+        error_response(
+            413,
+            format!("request body is too large, the limit is {} bytes", limit),
         )
-        .ok()?
-    };
-    let mut highlighter = Highlighter::new();
-    let hl_names = hl_cfg.names().to_vec();
-    let svg_attributes: Vec<_> = hl_names
-        .iter()
-        .map(|name| format!(r#"class="{}""#, name.replace(".", " ")))
-        .collect();
-    hl_cfg.configure(&hl_names.to_vec());
-    log::info!("Highlighting...");
-    let events = highlighter
-        .highlight(&hl_cfg, source_code.as_bytes(), None, |_| None)
-        .ok()?;
-
-    log::info!("Creating renderer...");
-    let attribute_callback = |hl: &Highlight| svg_attributes[hl.0].clone();
-    let mut svg_renderer = SvgRenderer::new(&source_code, &attribute_callback);
-
-    let stylesheet =
-        fs::read_to_string("assets/stylesheets/ayu-vim.css").ok()?;
-    log::info!("Rendering SVG...");
-    svg_renderer.render(events, stylesheet).ok()?;
-    let tree =
-        usvg::Tree::from_str(&svg_renderer.get_svg(), &USVG_TREE_OPTIONS)
-            .ok()?;
-    let (width, height) = svg_renderer.get_picture_size();
-    let mut pixmap = tiny_skia::Pixmap::new(width as u32, height as u32)?;
-    log::info!("Rendering PNG...");
-    resvg::render(&tree, usvg::FitTo::Original, pixmap.as_mut())?;
-    Some(Body::from(pixmap.encode_png().ok()?))
+    };
+    let content_length = body.size_hint().exact().unwrap_or(0) as usize;
+    if limit > 0 && content_length > limit {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::with_capacity(content_length);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| {
+            log::warn!("Failed to read request body: {}", err);
+            error_response(400, format!("failed to read request body: {}", err))
+        })?;
+        if limit > 0 && bytes.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Read the request body as text, failing with 400 if it isn't UTF-8
+async fn read_text_body(
+    body: Body,
+    limit: usize,
+) -> Result<String, Response<Body>> {
+    let bytes = read_body(body, limit).await?;
+    String::from_utf8(bytes).map_err(|err| {
+        error_response(
+            400,
+            format!("request body is not valid UTF-8: {}", err.utf8_error()),
+        )
+    })
+}
+
+/// Check `If-None-Match` header value against the ETag of the response.
+/// `*` matches any representation, so it only counts once one `exists`
+fn matches_etag(if_none_match: Option<&str>, etag: &str, exists: bool) -> bool {
+    if_none_match.map_or(false, |value| {
+        value.split(',').map(str::trim).any(|tag| {
+            (tag == "*" && exists) || tag.trim_start_matches("W/") == etag
+        })
+    })
+}
+
+fn if_none_match(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get("If-None-Match")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// Render on a blocking thread, so that a slow render doesn't hold up other
+/// connections
+async fn render_response(
+    options: RenderOptions,
+    source_code: String,
+    if_none_match: Option<String>,
+) -> Response<Body> {
+    let content_type = options.format.content_type();
+    let renderer = Renderer::from_options(options);
+    let key = cache::cache_key(&source_code, &renderer);
+    let etag = format!(r#""{}""#, key);
+    let cache_control =
+        format!("public, max-age={}", config::get().cache.max_age);
+    let not_modified = |etag: String, cache_control: String| {
+        Response::builder()
+            .status(304)
+            .header("ETag", etag)
+            .header("Cache-Control", cache_control)
+            .body(Body::empty())
+            .unwrap()
+    };
+    if matches_etag(if_none_match.as_deref(), &etag, false) {
+        return not_modified(etag, cache_control);
+    }
+    let rendered = tokio::task::spawn_blocking(move || {
+        cache::render_with_key(&key, &renderer, &source_code)
+    })
+    .await;
+    match rendered {
+        Ok(Ok(_)) if matches_etag(if_none_match.as_deref(), &etag, true) => {
+            not_modified(etag, cache_control)
+        }
+        Ok(Ok(image)) => Response::builder()
+            .status(200)
+            .header("Content-Type", content_type)
+            .header("ETag", etag)
+            .header("Cache-Control", cache_control)
+            .body(Body::from(image.as_ref().clone()))
+            .unwrap(),
+        Ok(Err(err)) => render_error_response(err),
+        Err(err) => {
+            log::error!("{}", err);
+            error_response(500, Body::empty())
+        }
+    }
+}
+
+async fn highlight(req: Request<Body>) -> Response<Body> {
+    let qs = req
+        .uri()
+        .query()
+        .map(parse_query_string)
+        .unwrap_or_default();
+    let options = match RenderOptions::from_map(&qs) {
+        Ok(options) => options,
+        Err(err) => return render_error_response(err),
+    };
+    let if_none_match = if_none_match(&req);
+    let limits = &config::get().limits;
+    let source_code =
+        match read_text_body(req.into_body(), limits.max_body_bytes).await {
+            Ok(source_code) => source_code,
+            Err(response) => return response,
+        };
+    render_response(options, source_code, if_none_match).await
+}
+
+/// `GET /render?lang=...&code=...` with the code encoded as described in
+/// [`url_code`], so that images can be embedded by URL
+async fn highlight_url(req: Request<Body>) -> Response<Body> {
+    let limits = &config::get().limits;
+    let url_length = req.uri().to_string().len();
+    if limits.max_url_length > 0 && url_length > limits.max_url_length {
+        return error_response(
+            414,
+            format!(
+                "URL is too long, the limit is {} bytes",
+                limits.max_url_length
+            ),
+        );
+    }
+    let qs = req
+        .uri()
+        .query()
+        .map(parse_query_string)
+        .unwrap_or_default();
+    let options = match RenderOptions::from_map(&qs) {
+        Ok(options) => options,
+        Err(err) => return render_error_response(err),
+    };
+    let source_code = match qs.get("code") {
+        Some(code) => match url_code::decode(code, limits.max_body_bytes) {
+            Ok(source_code) => source_code,
+            Err(err) => {
+                return error_response(
+                    400,
+                    format!("cannot decode `code` parameter: {}", err),
+                );
+            }
+        },
+        None => {
+            return error_response(400, "missing required parameter `code`");
+        }
+    };
+    render_response(options, source_code, if_none_match(&req)).await
+}
+
+async fn highlight_batch(req: Request<Body>) -> Response<Body> {
+    let limits = &config::get().limits;
+    let bytes = match read_body(req.into_body(), limits.max_batch_bytes).await {
+        Ok(bytes) => bytes,
+        Err(response) => return response,
+    };
+    let manifest = match Manifest::parse(&bytes) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            return error_response(400, format!("invalid manifest: {}", err));
+        }
+    };
+    if manifest.items.len() > limits.max_batch_items {
+        return error_response(
+            413,
+            format!(
+                "too many items, at most {} are allowed",
+                limits.max_batch_items
+            ),
+        );
+    }
+    let workers = limits.batch_workers;
+    let archive = tokio::task::spawn_blocking(move || {
+        let outputs = batch::run(manifest, None, workers);
+        batch::write_tar(&outputs)
+    })
+    .await;
+    match archive {
+        Ok(Ok(archive)) => Response::builder()
+            .status(200)
+            .header("Content-Type", "application/x-tar")
+            .body(Body::from(archive))
+            .unwrap(),
+        Ok(Err(err)) => {
+            log::error!("{}", err);
+            error_response(500, Body::empty())
+        }
+        Err(err) => {
+            log::error!("{}", err);
+            error_response(500, Body::empty())
+        }
+    }
+}
+
+fn init_snippet_store() {
+    let config = &config::get().snippets;
+    if !config.enabled {
+        return;
+    }
+    let store = match snippets::open_store(config) {
+        Ok(store) => store,
+        Err(err) => {
+            log::error!("{}", err);
+            process::exit(1);
+        }
+    };
+    if SNIPPETS.set(store).is_err() {
+        return;
+    }
+    let purge_interval = Duration::from_secs(config.purge_interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(purge_interval);
+        loop {
+            interval.tick().await;
+            let store = SNIPPETS.get().expect("Snippet store is initialized");
+            match store.purge_expired() {
+                Ok(0) => {}
+                Ok(count) => log::info!("Removed {} expired snippets", count),
+                Err(err) => log::error!("{}", err),
+            }
+        }
+    });
+}
+
+async fn save_snippet(req: Request<Body>) -> Response<Body> {
+    let store = match SNIPPETS.get() {
+        Some(store) => store,
+        None => return error_response(404, Body::empty()),
+    };
+    let qs = req
+        .uri()
+        .query()
+        .map(parse_query_string)
+        .unwrap_or_default();
+    let language = match qs.get("lang") {
+        Some(language) if get_language(language).is_some() => language,
+        Some(language) => {
+            return render_error_response(Error::UnknownLanguage(
+                language.clone(),
+            ))
+        }
+        None => return render_error_response(Error::MissingParameter("lang")),
+    };
+    let config = &config::get().snippets;
+    let ttl = match qs.get("ttl").map(|ttl| ttl.parse::<u64>()) {
+        Some(Ok(ttl)) => ttl,
+        Some(Err(_)) => {
+            return error_response(400, "`ttl` must be a number of seconds")
+        }
+        None => config.default_ttl,
+    };
+    let ttl = match (ttl, config.max_ttl) {
+        (0, 0) => None,
+        (0, max_ttl) => Some(max_ttl),
+        (ttl, 0) => Some(ttl),
+        (ttl, max_ttl) => Some(ttl.min(max_ttl)),
+    };
+    let limits = &config::get().limits;
+    let code =
+        match read_text_body(req.into_body(), limits.max_body_bytes).await {
+            Ok(code) => code,
+            Err(response) => return response,
+        };
+    let snippet = Snippet::new(language.clone(), code, ttl);
+    let id = snippet.id();
+    let snippet = match store.put(&id, &snippet) {
+        Ok(snippet) => snippet,
+        Err(err) => {
+            log::error!("{}", err);
+            return error_response(500, "failed to save snippet");
+        }
+    };
+    let body = serde_json::json!({
+        "id": id,
+        "url": format!("/s/{}", id),
+        "expires_at": snippet.expires_at,
+    });
+    Response::builder()
+        .status(201)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// `GET /s/<id>` returns the code, `GET /s/<id>.<format>` renders it
+async fn get_snippet(req: Request<Body>) -> Response<Body> {
+    let store = match SNIPPETS.get() {
+        Some(store) => store,
+        None => return error_response(404, Body::empty()),
+    };
+    let name = req.uri().path().trim_start_matches("/s/");
+    let (id, format) = match name.find('.') {
+        Some(idx) => (&name[..idx], Some(&name[idx + 1..])),
+        None => (name, None),
+    };
+    if !snippets::is_valid_id(id) {
+        return error_response(404, Body::empty());
+    }
+    let snippet = match store.get(id) {
+        Ok(Some(snippet)) => snippet,
+        Ok(None) => return error_response(404, "snippet not found"),
+        Err(err) => {
+            log::error!("{}", err);
+            return error_response(500, "failed to load snippet");
+        }
+    };
+    let format = match format {
+        Some(format) => format,
+        None => {
+            return Response::builder()
+                .status(200)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(Body::from(snippet.code))
+                .unwrap()
+        }
+    };
+    let mut qs = req
+        .uri()
+        .query()
+        .map(parse_query_string)
+        .unwrap_or_default();
+    // Rendered as saved, the query string can't pick another language
+    qs.insert("lang".to_string(), snippet.language.clone());
+    qs.insert("format".to_string(), format.to_string());
+    let options = match RenderOptions::from_map(&qs) {
+        Ok(options) => options,
+        Err(err) => return render_error_response(err),
+    };
+    render_response(options, snippet.code, if_none_match(&req)).await
 }
 
 async fn serve(req: Request<Body>) -> http::Result<Response<Body>> {
     match (req.uri().path(), req.method()) {
-        ("/", &Method::POST) => {
-            if let Some(body) = highlight(req).await {
-                Response::builder()
-                    .status(200)
-                    .header("Content-Type", "image/png")
-                    .body(body)
-            } else {
-                Response::builder().status(400).body(Body::empty())
-            }
+        ("/", &Method::POST) => Ok(highlight(req).await),
+        ("/render", &Method::GET) => Ok(highlight_url(req).await),
+        ("/v1/batch", &Method::POST) => Ok(highlight_batch(req).await),
+        ("/v1/snippets", &Method::POST) => Ok(save_snippet(req).await),
+        (path, &Method::GET) if path.starts_with("/s/") => {
+            Ok(get_snippet(req).await)
         }
         _ => Response::builder().status(404).body(Body::empty()),
     }
@@ -0,0 +1,77 @@
+//! Text measurement. Widths are counted in monospace columns: most
+//! characters take one, East Asian wide characters and emoji take two and
+//! combining marks take none. Columns are converted to pixels with the
+//! advance of the requested font, read from the font file itself.
+
+use crate::config;
+use crate::utils::USVG_TREE_OPTIONS;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, sync::Mutex};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+use usvg::fontdb;
+
+/// Metrics of a monospace font, relative to the font size
+#[derive(Debug, Clone, Copy)]
+pub struct FontMetrics {
+    /// Advance of one column
+    pub advance: f32,
+    /// Height above the baseline
+    pub ascender: f32,
+    /// Depth below the baseline, positive
+    pub descender: f32,
+}
+
+static FONT_METRICS: Lazy<Mutex<HashMap<String, FontMetrics>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn load_font_metrics(family: &str) -> Option<FontMetrics> {
+    let fontdb = &USVG_TREE_OPTIONS.fontdb;
+    let id = fontdb.query(&fontdb::Query {
+        families: &[fontdb::Family::Name(family)],
+        weight: fontdb::Weight::NORMAL,
+        stretch: fontdb::Stretch::Normal,
+        style: fontdb::Style::Normal,
+    })?;
+    fontdb
+        .with_face_data(id, |data, index| {
+            let face = ttf_parser::Face::from_slice(data, index).ok()?;
+            let units_per_em = face.units_per_em()? as f32;
+            let glyph = face.glyph_index('0')?;
+            Some(FontMetrics {
+                advance: face.glyph_hor_advance(glyph)? as f32 / units_per_em,
+                ascender: face.ascender() as f32 / units_per_em,
+                descender: -face.descender() as f32 / units_per_em,
+            })
+        })
+        .flatten()
+}
+
+/// Metrics of the regular face of `family`, read on first use
+pub fn font_metrics(family: &str) -> FontMetrics {
+    let mut cache = FONT_METRICS.lock().unwrap();
+    *cache.entry(family.to_string()).or_insert_with(|| {
+        load_font_metrics(family).unwrap_or_else(|| {
+            log::warn!(
+                "Cannot read metrics of `{}`, using font_aspect_ratio",
+                family
+            );
+            FontMetrics {
+                advance: config::get().defaults.font_aspect_ratio,
+                ascender: 0.8,
+                descender: 0.2,
+            }
+        })
+    })
+}
+
+/// Columns taken by a grapheme cluster
+pub fn grapheme_width(grapheme: &str) -> usize {
+    // Emoji sequences report the sum of their parts
+    grapheme.width().min(2)
+}
+
+/// Columns taken by a line of text without tabs
+pub fn str_width(line: &str) -> usize {
+    line.graphemes(true).map(grapheme_width).sum()
+}
@@ -0,0 +1,350 @@
+//! PDF output. Text is drawn with the requested font embedded, subset to
+//! the characters in use, so that it stays selectable and searchable.
+//! Characters missing from the font are drawn with the regular face of the
+//! first fallback family that has them, embedded the same way. Fonts with
+//! CFF outlines can't be subset and are embedded whole.
+//!
+//! Pages hold the plain code block: there is no window frame, shadow or
+//! outer background, lines aren't wrapped, and text isn't shaped, so
+//! OpenType features and ligatures don't apply. Options asking for these
+//! are rejected rather than ignored. `title` becomes the document title.
+
+use crate::config;
+use crate::fonts;
+use crate::frame::WindowStyle;
+use crate::line_ranges::Row;
+use crate::metrics::{self, str_width};
+use crate::render::{highlight_error, Error, RenderOptions};
+use crate::styled_text::{styled_lines, Palette};
+use crate::theme::{Style, Theme};
+use crate::utils::USVG_TREE_OPTIONS;
+use printpdf::{
+    Color, IndirectFontRef, Line, Mm, PdfDocument, PdfLayerReference, Point,
+    Pt, Rgb,
+};
+use std::collections::{BTreeSet, HashMap};
+use tree_sitter_highlight::HighlightEvent;
+
+/// Font size when `font_size` isn't set, in points
+const DEFAULT_FONT_SIZE: f64 = 10.;
+/// Largest width and height of a page in PDF 1.7, in points
+const MAX_PAGE_SIZE: f64 = 14400.;
+/// Distance between baselines relative to the font size
+const LINE_HEIGHT: f64 = 1.2;
+
+fn pdf_color([r, g, b]: [u8; 3]) -> Color {
+    let channel = |c: u8| c as f64 / 255.;
+    Color::Rgb(Rgb::new(channel(r), channel(g), channel(b), None))
+}
+
+fn point(x: f64, y: f64) -> (Point, bool) {
+    (Point::new(Mm::from(Pt(x)), Mm::from(Pt(y))), false)
+}
+
+/// Filled rectangle, with coordinates in points
+fn fill_rect(
+    layer: &PdfLayerReference,
+    color: [u8; 3],
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+) {
+    layer.set_fill_color(pdf_color(color));
+    layer.add_shape(Line {
+        points: vec![
+            point(x, y),
+            point(x + width, y),
+            point(x + width, y + height),
+            point(x, y + height),
+        ],
+        is_closed: true,
+        has_fill: true,
+        has_stroke: false,
+        is_clipping_path: false,
+    });
+}
+
+/// Reject options that PDF output can't draw
+fn check_options(options: &RenderOptions) -> Result<(), Error> {
+    let frame = &options.frame;
+    let unsupported = if frame.window != WindowStyle::None {
+        Some(("window", format!("{:?}", frame.window).to_lowercase()))
+    } else if frame.margin > 0 {
+        Some(("margin", frame.margin.to_string()))
+    } else if frame.radius > 0 {
+        Some(("radius", frame.radius.to_string()))
+    } else if frame.shadow {
+        Some(("shadow", true.to_string()))
+    } else if let Some(color) = &frame.background {
+        Some(("outer_background", color.clone()))
+    } else if options.wrap > 0 {
+        Some(("wrap", options.wrap.to_string()))
+    } else if !options.font_features.settings.is_empty() {
+        let tags: Vec<_> = options
+            .font_features
+            .settings
+            .iter()
+            .map(|(tag, value)| format!("{}={}", tag, value))
+            .collect();
+        Some(("font_features", tags.join(",")))
+    } else {
+        None
+    };
+    match unsupported {
+        Some((name, value)) => Err(Error::InvalidParameter(name, value)),
+        None => Ok(()),
+    }
+}
+
+/// Split `text` into runs sharing a font: `font`, or the first of
+/// `fallbacks` whose characters include the one at hand
+pub(crate) fn font_runs<'a, F>(
+    text: &'a str,
+    font: &'a F,
+    fallbacks: &'a [(BTreeSet<char>, F)],
+) -> Vec<(&'a str, &'a F)> {
+    let font_for = |c: char| {
+        fallbacks
+            .iter()
+            .find(|(chars, _)| chars.contains(&c))
+            .map_or(font, |(_, fallback)| fallback)
+    };
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut run_font = font;
+    for (idx, c) in text.char_indices() {
+        let char_font = font_for(c);
+        if !std::ptr::eq(char_font, run_font) {
+            if idx > start {
+                runs.push((&text[start..idx], run_font));
+            }
+            start = idx;
+            run_font = char_font;
+        }
+    }
+    if start < text.len() {
+        runs.push((&text[start..], run_font));
+    }
+    runs
+}
+
+fn pdf_error(err: impl std::fmt::Display) -> Error {
+    Error::Pdf(err.to_string())
+}
+
+/// Render highlight events into a PDF document. With `page_lines` set,
+/// rows are split into pages of that many rows, otherwise a single page
+/// fits the whole code. Either way, pages are split further to stay within
+/// the PDF page size limit
+pub fn render<I>(
+    events: I,
+    source: &str,
+    names: &[String],
+    theme: &Theme,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, tree_sitter_highlight::Error>>,
+{
+    check_options(options)?;
+    let lines = styled_lines(events, source, names, theme, options.tab_width)
+        .map_err(highlight_error)?;
+    let palette = Palette::new(theme);
+
+    let rows =
+        options
+            .lines
+            .rows(options.start_line, lines.len(), options.ellipsis);
+    let last_shown = rows.iter().rev().find_map(|row| match row {
+        Row::Line(idx) => Some(*idx),
+        Row::Elided => None,
+    });
+    let number_width = if options.line_numbers {
+        (options.start_line + last_shown.unwrap_or(0))
+            .to_string()
+            .len()
+            + 1
+    } else {
+        0
+    };
+    let code_width = rows
+        .iter()
+        .filter_map(|row| match row {
+            Row::Line(idx) => Some(&lines[*idx]),
+            Row::Elided => None,
+        })
+        .map(|line| line.iter().map(|(text, _)| str_width(text)).sum())
+        .max()
+        .unwrap_or(0);
+
+    let metrics = metrics::font_metrics(&options.font);
+    let font_size = options
+        .font_size
+        .map_or(DEFAULT_FONT_SIZE, |size| size as f64);
+    let line_height = font_size * LINE_HEIGHT;
+    let ascender = metrics.ascender as f64 * font_size;
+    let descender = metrics.descender as f64 * font_size;
+    let half_leading = (line_height - ascender - descender) / 2.;
+    let char_width = metrics.advance as f64 * font_size;
+    let padding = options.padding as f64;
+    // Rows that fit the largest page viewers accept
+    let max_page_rows =
+        (((MAX_PAGE_SIZE - 2. * padding) / line_height) as usize).max(1);
+    let page_rows = match options.page_lines {
+        0 => rows.len().max(1),
+        page_lines => page_lines,
+    }
+    .min(max_page_rows);
+    let width = (number_width + code_width) as f64 * char_width + 2. * padding;
+    let height = page_rows as f64 * line_height + 2. * padding;
+
+    let title = options.frame.title.as_deref().unwrap_or("Code");
+    let (doc, page, layer) = PdfDocument::new(
+        title,
+        Mm::from(Pt(width)),
+        Mm::from(Pt(height)),
+        "Code",
+    );
+
+    let chars: BTreeSet<char> = source
+        .chars()
+        .chain("0123456789…".chars())
+        .filter(|c| !c.is_control())
+        .collect();
+    let regular = fonts::subset_face(&options.font, false, false, &chars)
+        .ok_or_else(|| {
+            pdf_error(format!("cannot embed font `{}`", options.font))
+        })?;
+    let regular = doc.add_external_font(&regular[..]).map_err(pdf_error)?;
+    // Faces for the styles in use, the regular one stands in for missing ones
+    let mut faces: HashMap<(bool, bool), IndirectFontRef> = HashMap::new();
+    for (_, style) in lines.iter().flatten() {
+        let key = (style.bold, style.italic);
+        if key == (false, false) || faces.contains_key(&key) {
+            continue;
+        }
+        let face = match fonts::subset_face(&options.font, key.0, key.1, &chars)
+        {
+            Some(data) => {
+                doc.add_external_font(&data[..]).map_err(pdf_error)?
+            }
+            None => regular.clone(),
+        };
+        faces.insert(key, face);
+    }
+    let font_for = |style: &Style| {
+        faces.get(&(style.bold, style.italic)).unwrap_or(&regular)
+    };
+    // Regular faces of the fallback families for what the font lacks
+    let families = std::iter::once(options.font.as_str()).chain(
+        config::get()
+            .assets
+            .font_fallbacks
+            .iter()
+            .map(String::as_str),
+    );
+    let mut fallbacks = Vec::new();
+    for (family, covered) in
+        fonts::family_coverage(&USVG_TREE_OPTIONS.fontdb, families, &chars)
+    {
+        if family == options.font {
+            continue;
+        }
+        match fonts::subset_face(family, false, false, &covered) {
+            Some(data) => {
+                let font =
+                    doc.add_external_font(&data[..]).map_err(pdf_error)?;
+                fallbacks.push((covered, font));
+            }
+            None => log::warn!("Cannot embed font `{}`", family),
+        }
+    }
+
+    let mut layer = doc.get_page(page).get_layer(layer);
+    for (page_idx, chunk) in rows.chunks(page_rows).enumerate() {
+        if page_idx > 0 {
+            let (page, page_layer) =
+                doc.add_page(Mm::from(Pt(width)), Mm::from(Pt(height)), "Code");
+            layer = doc.get_page(page).get_layer(page_layer);
+        }
+        fill_rect(&layer, palette.background, 0., 0., width, height);
+        for (row_idx, row) in chunk.iter().enumerate() {
+            let top = height - padding - row_idx as f64 * line_height;
+            let baseline = top - half_leading - ascender;
+            let idx = match row {
+                Row::Line(idx) => *idx,
+                Row::Elided if options.ellipsis => {
+                    layer.begin_text_section();
+                    layer.set_font(&regular, font_size);
+                    layer.set_text_cursor(
+                        Mm::from(Pt(padding)),
+                        Mm::from(Pt(baseline)),
+                    );
+                    layer.set_fill_color(pdf_color(palette.gutter));
+                    layer.write_text(
+                        format!(
+                            "{:>width$}",
+                            "…",
+                            width = number_width.saturating_sub(1)
+                        ),
+                        &regular,
+                    );
+                    layer.end_text_section();
+                    continue;
+                }
+                Row::Elided => {
+                    let y = top - line_height / 2.;
+                    fill_rect(&layer, palette.gutter, 0., y, width, 0.5);
+                    continue;
+                }
+            };
+            let line_number = options.start_line + idx;
+            let is_highlighted = options.highlight_lines.contains(line_number);
+            if let Some(color) =
+                palette.line_highlight.filter(|_| is_highlighted)
+            {
+                let y = top - line_height;
+                fill_rect(&layer, color, 0., y, width, line_height);
+            }
+            let is_dimmed = options.dim && !is_highlighted;
+            let paint = |color: [u8; 3]| {
+                if is_dimmed {
+                    palette.fade(color)
+                } else {
+                    color
+                }
+            };
+
+            layer.begin_text_section();
+            layer.set_font(&regular, font_size);
+            layer
+                .set_text_cursor(Mm::from(Pt(padding)), Mm::from(Pt(baseline)));
+            if number_width > 0 {
+                layer.set_fill_color(pdf_color(paint(palette.gutter)));
+                layer.write_text(
+                    format!(
+                        "{:>width$} ",
+                        line_number,
+                        width = number_width - 1
+                    ),
+                    &regular,
+                );
+            }
+            let mut current_font = &regular;
+            for (text, style) in &lines[idx] {
+                layer.set_fill_color(pdf_color(paint(palette.color(style))));
+                for (run, font) in font_runs(text, font_for(style), &fallbacks)
+                {
+                    if !std::ptr::eq(font, current_font) {
+                        layer.set_font(font, font_size);
+                        current_font = font;
+                    }
+                    layer.write_text(run, font);
+                }
+            }
+            layer.end_text_section();
+        }
+    }
+    doc.save_to_bytes().map_err(pdf_error)
+}
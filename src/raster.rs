@@ -0,0 +1,210 @@
+//! Encoding of rendered pictures into raster formats
+
+use crate::render::OutputFormat;
+use std::{collections::HashMap, convert::TryFrom};
+use tiny_skia::Pixmap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "fast" => Some(Self::Fast),
+            "default" => Some(Self::Default),
+            "best" => Some(Self::Best),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RasterOptions {
+    /// Quality of lossy formats, from 1 to 100
+    pub quality: u8,
+    /// Effort put into PNG compression
+    pub compression: Compression,
+    /// Reduce PNG output to at most 256 colors
+    pub palette: bool,
+    /// Encode WebP without loss, ignoring `quality`
+    pub lossless: bool,
+}
+
+impl Default for RasterOptions {
+    fn default() -> Self {
+        Self {
+            quality: 90,
+            compression: Compression::Default,
+            palette: false,
+            lossless: false,
+        }
+    }
+}
+
+/// RGBA without premultiplied alpha, as encoders expect
+fn demultiply(pixmap: &Pixmap) -> Vec<u8> {
+    let mut data = pixmap.data().to_vec();
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        if alpha == 0 || alpha == 255 {
+            continue;
+        }
+        for channel in &mut pixel[..3] {
+            *channel = ((*channel as u32 * 255 + alpha / 2) / alpha) as u8;
+        }
+    }
+    data
+}
+
+/// Pick up to 256 colors out of `rgba` and map every pixel to the closest
+/// one. Code has few colors besides anti-aliasing, so the most frequent
+/// colors, grouped by 5 significant bits, are a good enough palette
+pub(crate) fn quantize(rgba: &[u8]) -> (Vec<[u8; 4]>, Vec<u8>) {
+    let mut counts: HashMap<[u8; 4], usize> = HashMap::new();
+    for pixel in rgba.chunks_exact(4) {
+        *counts
+            .entry([pixel[0], pixel[1], pixel[2], pixel[3]])
+            .or_default() += 1;
+    }
+    let palette: Vec<[u8; 4]> = if counts.len() <= 256 {
+        // Most frequent first, like the buckets below, so that the output
+        // doesn't depend on the order of the map
+        let mut colors: Vec<_> = counts.iter().collect();
+        colors.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        colors.into_iter().map(|(color, _)| *color).collect()
+    } else {
+        // Bucket key, then its pixel count and sums of channels
+        let mut buckets: HashMap<_, (usize, [usize; 4])> = HashMap::new();
+        for (color, count) in &counts {
+            let key =
+                [color[0] >> 3, color[1] >> 3, color[2] >> 3, color[3] >> 3];
+            let bucket = buckets.entry(key).or_default();
+            bucket.0 += count;
+            for (sum, &channel) in bucket.1.iter_mut().zip(color) {
+                *sum += channel as usize * count;
+            }
+        }
+        let mut buckets: Vec<_> = buckets.into_iter().collect();
+        buckets.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(&b.0)));
+        buckets
+            .iter()
+            .take(256)
+            .map(|(_, (count, sums))| {
+                let mut color = [0; 4];
+                for (channel, sum) in color.iter_mut().zip(sums) {
+                    *channel = (sum / count) as u8;
+                }
+                color
+            })
+            .collect()
+    };
+
+    let distance = |a: &[u8; 4], b: &[u8; 4]| -> u32 {
+        a.iter()
+            .zip(b)
+            .map(|(&x, &y)| (x as i32 - y as i32).pow(2) as u32)
+            .sum()
+    };
+    let indices: HashMap<[u8; 4], u8> = counts
+        .keys()
+        .map(|color| {
+            let (idx, _) = palette
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| distance(color, *entry))
+                .expect("Palette is not empty");
+            (*color, idx as u8)
+        })
+        .collect();
+    let pixels = rgba
+        .chunks_exact(4)
+        .map(|pixel| indices[&[pixel[0], pixel[1], pixel[2], pixel[3]]])
+        .collect();
+    (palette, pixels)
+}
+
+fn encode_png(
+    pixmap: &Pixmap,
+    options: &RasterOptions,
+) -> Result<Vec<u8>, String> {
+    let rgba = demultiply(pixmap);
+    let mut png = Vec::new();
+    let mut encoder =
+        png::Encoder::new(&mut png, pixmap.width(), pixmap.height());
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match options.compression {
+        Compression::Fast => png::Compression::Fast,
+        Compression::Default => png::Compression::Default,
+        Compression::Best => png::Compression::Best,
+    });
+    let data = if options.palette {
+        let (palette, pixels) = quantize(&rgba);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(
+            palette
+                .iter()
+                .flat_map(|color| color[..3].to_vec())
+                .collect(),
+        );
+        encoder.set_trns(palette.iter().map(|color| color[3]).collect());
+        pixels
+    } else {
+        encoder.set_color(png::ColorType::RGBA);
+        rgba
+    };
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    writer
+        .write_image_data(&data)
+        .map_err(|err| err.to_string())?;
+    drop(writer);
+    Ok(png)
+}
+
+fn encode_jpeg(
+    pixmap: &Pixmap,
+    options: &RasterOptions,
+) -> Result<Vec<u8>, String> {
+    let too_large = || "picture is too large for JPEG".to_string();
+    let width = u16::try_from(pixmap.width()).map_err(|_| too_large())?;
+    let height = u16::try_from(pixmap.height()).map_err(|_| too_large())?;
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, options.quality)
+        .encode(
+            &demultiply(pixmap),
+            width,
+            height,
+            jpeg_encoder::ColorType::Rgba,
+        )
+        .map_err(|err| err.to_string())?;
+    Ok(jpeg)
+}
+
+fn encode_webp(pixmap: &Pixmap, options: &RasterOptions) -> Vec<u8> {
+    let rgba = demultiply(pixmap);
+    let encoder =
+        webp::Encoder::from_rgba(&rgba, pixmap.width(), pixmap.height());
+    let webp = if options.lossless {
+        encoder.encode_lossless()
+    } else {
+        encoder.encode(options.quality as f32)
+    };
+    webp.to_vec()
+}
+
+/// Encode `pixmap` into `format`. Formats without alpha expect the pixmap
+/// to be opaque already
+pub fn encode(
+    pixmap: &Pixmap,
+    format: OutputFormat,
+    options: &RasterOptions,
+) -> Result<Vec<u8>, String> {
+    match format {
+        OutputFormat::Jpeg => encode_jpeg(pixmap, options),
+        OutputFormat::Webp => Ok(encode_webp(pixmap, options)),
+        _ => encode_png(pixmap, options),
+    }
+}
@@ -0,0 +1,889 @@
+use crate::ansi_renderer;
+use crate::config::{self, LimitsConfig};
+use crate::fonts::{self, FontFeatures};
+use crate::frame::{Frame, WindowStyle};
+use crate::html_renderer;
+use crate::json_renderer;
+use crate::latex_renderer;
+use crate::line_ranges::LineRanges;
+use crate::lsp_renderer;
+use crate::metrics;
+use crate::pdf_renderer;
+use crate::raster::{self, Compression, RasterOptions};
+use crate::rtf_renderer;
+use crate::svg_renderer::SvgRenderer;
+use crate::theme::{parse_color, Theme};
+use crate::utils::{get_language, USVG_TREE_OPTIONS, USVG_VECTOR_OPTIONS};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tree_sitter_highlight::{Highlight, HighlightConfiguration, Highlighter};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Svg,
+    Pdf,
+    Html,
+    Ansi,
+    Latex,
+    Rtf,
+    Json,
+    LspSemanticTokens,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "png" => Some(Self::Png),
+            "jpeg" => Some(Self::Jpeg),
+            "webp" => Some(Self::Webp),
+            "svg" => Some(Self::Svg),
+            "pdf" => Some(Self::Pdf),
+            "html" => Some(Self::Html),
+            "ansi" => Some(Self::Ansi),
+            "latex" => Some(Self::Latex),
+            "rtf" => Some(Self::Rtf),
+            "json" => Some(Self::Json),
+            "lsp-semantic-tokens" => Some(Self::LspSemanticTokens),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+            Self::Svg => "svg",
+            Self::Pdf => "pdf",
+            Self::Html => "html",
+            Self::Ansi => "txt",
+            Self::Latex => "tex",
+            Self::Rtf => "rtf",
+            Self::Json | Self::LspSemanticTokens => "json",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+            Self::Svg => "image/svg+xml",
+            Self::Pdf => "application/pdf",
+            Self::Html => "text/html; charset=utf-8",
+            Self::Ansi => "text/plain; charset=utf-8",
+            Self::Latex => "application/x-latex; charset=utf-8",
+            Self::Rtf => "application/rtf",
+            Self::Json | Self::LspSemanticTokens => "application/json",
+        }
+    }
+
+    pub fn is_raster(&self) -> bool {
+        matches!(self, Self::Png | Self::Jpeg | Self::Webp)
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        *self != Self::Jpeg
+    }
+}
+
+/// Options shared by the HTTP API and the command-line interface
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub language: String,
+    pub theme: String,
+    pub format: OutputFormat,
+    pub line_numbers: bool,
+    pub start_line: usize,
+    /// Lines to paint a background band behind, counted from `start_line`
+    pub highlight_lines: LineRanges,
+    /// Fade out lines not in `highlight_lines`
+    pub dim: bool,
+    /// Lines to render, counted from `start_line`. Every line if empty
+    pub lines: LineRanges,
+    /// Mark lines left out by `lines` with `…`
+    pub ellipsis: bool,
+    /// Columns between tab stops
+    pub tab_width: usize,
+    /// Column to soft-wrap long lines at, 0 disables wrapping. Only SVG and
+    /// PNG output is wrapped
+    pub wrap: usize,
+    /// Keep this font size and size the picture to the content, instead of
+    /// fitting the content into `defaults.picture_width`
+    pub font_size: Option<usize>,
+    /// Space around the content, in pixels
+    pub padding: usize,
+    /// Bounds of the picture width with fixed font size, 0 for none
+    pub min_width: usize,
+    pub max_width: usize,
+    /// Zoom of raster output, e.g. 2 for high density screens
+    pub scale: f32,
+    /// Window around the code in SVG and PNG output
+    pub frame: Frame,
+    /// Font family, one of the fonts in `assets.fonts`
+    pub font: String,
+    pub font_features: FontFeatures,
+    /// Embed the font into SVG output, subset to the glyphs in use
+    pub embed_font: bool,
+    /// Convert text of SVG output to outlines, so that it looks the same
+    /// in every viewer
+    pub text_as_paths: bool,
+    /// Encoding of PNG, JPEG and WebP output
+    pub raster: RasterOptions,
+    /// Rows per page of PDF output, 0 puts everything on one page
+    pub page_lines: usize,
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse an optional parameter, falling back to `default` if it's absent
+fn parse_param<T>(
+    params: &HashMap<String, String>,
+    name: &'static str,
+    default: T,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<T, Error> {
+    match params.get(name) {
+        Some(value) => parse(value)
+            .ok_or_else(|| Error::InvalidParameter(name, value.clone())),
+        None => Ok(default),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+fn parse_frame(params: &HashMap<String, String>) -> Result<Frame, Error> {
+    let some = |value: &str| Some(Some(value.to_string()));
+    Ok(Frame {
+        window: parse_param(
+            params,
+            "window",
+            WindowStyle::None,
+            WindowStyle::parse,
+        )?,
+        title: parse_param(params, "title", None, some)?,
+        margin: parse_param(params, "margin", 0, |value| {
+            parse_number(value).filter(|&margin| margin <= MAX_PADDING)
+        })?,
+        radius: parse_param(params, "radius", 0, parse_number)?,
+        shadow: parse_param(params, "shadow", false, parse_bool)?,
+        background: parse_param(params, "outer_background", None, |value| {
+            match value {
+                "transparent" => Some(None),
+                _ => some(value),
+            }
+        })?,
+    })
+}
+
+fn parse_font_features(
+    params: &HashMap<String, String>,
+) -> Result<FontFeatures, Error> {
+    Ok(FontFeatures {
+        ligatures: parse_param(params, "ligatures", true, parse_bool)?,
+        settings: parse_param(
+            params,
+            "font_features",
+            Vec::new(),
+            FontFeatures::parse_settings,
+        )?,
+    })
+}
+
+fn parse_raster(
+    params: &HashMap<String, String>,
+) -> Result<RasterOptions, Error> {
+    Ok(RasterOptions {
+        quality: parse_param(params, "quality", 90, |value| {
+            parse_number(value).filter(|quality| (1..=100).contains(quality))
+        })?,
+        compression: parse_param(
+            params,
+            "compression",
+            Compression::Default,
+            Compression::parse,
+        )?,
+        palette: parse_param(params, "palette", false, parse_bool)?,
+        lossless: parse_param(params, "lossless", false, parse_bool)?,
+    })
+}
+
+/// Reasonable bound for the zoom, the pixel limit applies on top of it
+const MAX_SCALE: f32 = 8.;
+/// Bounds for sizes in pixels, which would otherwise allow huge pictures
+/// from short code
+const MAX_FONT_SIZE: usize = 256;
+const MAX_PADDING: usize = 1024;
+
+/// `scale` or, failing that, `dpi` relative to the CSS 96 DPI
+fn parse_scale(params: &HashMap<String, String>) -> Result<f32, Error> {
+    let parse_positive = |value: &str| {
+        parse_number::<f32>(value).filter(|x| x.is_finite() && *x > 0.)
+    };
+    let dpi = parse_param(params, "dpi", 96., parse_positive)?;
+    let scale = parse_param(params, "scale", dpi / 96., parse_positive)?;
+    if scale > MAX_SCALE {
+        let name = if params.contains_key("scale") {
+            "scale"
+        } else {
+            "dpi"
+        };
+        return Err(Error::InvalidParameter(name, params[name].clone()));
+    }
+    Ok(scale)
+}
+
+impl RenderOptions {
+    /// Build options from `key=value` pairs, as found in the query string
+    pub fn from_map(params: &HashMap<String, String>) -> Result<Self, Error> {
+        let language = params
+            .get("lang")
+            .cloned()
+            .ok_or(Error::MissingParameter("lang"))?;
+        let config = config::get();
+        let theme = params
+            .get("theme")
+            .cloned()
+            .unwrap_or_else(|| config.defaults.theme.clone());
+        let format_name =
+            params.get("format").unwrap_or(&config.defaults.format);
+        let format = OutputFormat::parse(format_name)
+            .filter(|_| config.is_format_enabled(format_name))
+            .ok_or_else(|| Error::UnknownFormat(format_name.clone()))?;
+        Ok(Self {
+            language,
+            theme,
+            format,
+            line_numbers: parse_param(
+                params,
+                "line_numbers",
+                true,
+                parse_bool,
+            )?,
+            start_line: parse_param(params, "start_line", 1, parse_number)?,
+            highlight_lines: parse_param(
+                params,
+                "highlight_lines",
+                LineRanges::default(),
+                LineRanges::parse,
+            )?,
+            dim: parse_param(params, "dim", false, parse_bool)?,
+            lines: parse_param(
+                params,
+                "lines",
+                LineRanges::default(),
+                LineRanges::parse,
+            )?,
+            ellipsis: parse_param(params, "ellipsis", false, parse_bool)?,
+            tab_width: parse_param(
+                params,
+                "tab_width",
+                config.defaults.tab_width,
+                |value| parse_number(value).filter(|&width| width > 0),
+            )?,
+            wrap: parse_param(params, "wrap", 0, parse_number)?,
+            font_size: parse_param(params, "font_size", None, |value| {
+                parse_number(value)
+                    .filter(|&size| size > 0 && size <= MAX_FONT_SIZE)
+                    .map(Some)
+            })?,
+            padding: parse_param(params, "padding", 0, |value| {
+                parse_number(value).filter(|&padding| padding <= MAX_PADDING)
+            })?,
+            min_width: parse_param(params, "min_width", 0, parse_number)?,
+            max_width: parse_param(params, "max_width", 0, parse_number)?,
+            scale: parse_scale(params)?,
+            frame: parse_frame(params)?,
+            font: params
+                .get("font")
+                .cloned()
+                .unwrap_or_else(|| config.assets.font_family.clone()),
+            font_features: parse_font_features(params)?,
+            embed_font: parse_param(params, "embed_font", false, parse_bool)?,
+            text_as_paths: parse_param(
+                params,
+                "text_as_paths",
+                false,
+                parse_bool,
+            )?,
+            raster: parse_raster(params)?,
+            page_lines: parse_param(params, "page_lines", 0, parse_number)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MissingParameter(&'static str),
+    InvalidParameter(&'static str, String),
+    UnknownLanguage(String),
+    UnknownTheme(String),
+    UnknownFormat(String),
+    UnknownFont(String),
+    Query(tree_sitter::QueryError),
+    Highlight(tree_sitter_highlight::Error),
+    Svg(usvg::Error),
+    Raster(String),
+    Pdf(String),
+    TooManyLines {
+        max: usize,
+    },
+    LineTooLong {
+        line: usize,
+        max: usize,
+    },
+    PictureTooLarge {
+        width: usize,
+        height: usize,
+        max_pixels: usize,
+    },
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingParameter(name) => {
+                write!(f, "missing required parameter `{}`", name)
+            }
+            Self::InvalidParameter(name, value) => {
+                write!(f, "invalid value `{}` of parameter `{}`", value, name)
+            }
+            Self::UnknownLanguage(name) => {
+                write!(f, "unknown language `{}`", name)
+            }
+            Self::UnknownTheme(name) => write!(f, "unknown theme `{}`", name),
+            Self::UnknownFormat(name) => {
+                write!(f, "unknown or disabled output format `{}`", name)
+            }
+            Self::UnknownFont(name) => write!(f, "unknown font `{}`", name),
+            Self::Query(err) => write!(f, "invalid highlight query: {}", err),
+            Self::Highlight(err) => {
+                write!(f, "failed to highlight source: {:?}", err)
+            }
+            Self::Svg(err) => write!(f, "failed to parse SVG: {}", err),
+            Self::Raster(msg) => write!(f, "failed to render image: {}", msg),
+            Self::Pdf(msg) => write!(f, "failed to render PDF: {}", msg),
+            Self::TooManyLines { max } => {
+                write!(f, "source has too many lines, the limit is {}", max)
+            }
+            Self::LineTooLong { line, max } => write!(
+                f,
+                "line {} is too long, the limit is {} characters",
+                line, max
+            ),
+            Self::PictureTooLarge {
+                width,
+                height,
+                max_pixels,
+            } => write!(
+                f,
+                "picture of {}x{} is too large, the limit is {} pixels",
+                width, height, max_pixels
+            ),
+            Self::Timeout => write!(f, "rendering took too long"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+static HIGHLIGHT_CONFIGS: Lazy<
+    Mutex<HashMap<String, Arc<HighlightConfiguration>>>,
+> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Get highlight configuration for the language, building it on first use
+pub fn highlight_config(
+    language_name: &str,
+) -> Result<Arc<HighlightConfiguration>, Error> {
+    if let Some(hl_cfg) = HIGHLIGHT_CONFIGS.lock().unwrap().get(language_name) {
+        return Ok(hl_cfg.clone());
+    }
+    let cfg = get_language(language_name)
+        .ok_or_else(|| Error::UnknownLanguage(language_name.to_string()))?;
+    let mut hl_cfg = HighlightConfiguration::new(
+        cfg.language,
+        &cfg.highlight_query,
+        &cfg.injections_query,
+        &cfg.locals_query,
+    )
+    .map_err(Error::Query)?;
+    let hl_names = hl_cfg.names().to_vec();
+    hl_cfg.configure(&hl_names);
+    let hl_cfg = Arc::new(hl_cfg);
+    HIGHLIGHT_CONFIGS
+        .lock()
+        .unwrap()
+        .insert(language_name.to_string(), hl_cfg.clone());
+    Ok(hl_cfg)
+}
+
+/// Renders source code into an image. Configured with a builder:
+///
+/// ```no_run
+/// use tree_sitter_highlight_server::{OutputFormat, Renderer};
+///
+/// let svg = Renderer::new("rust")
+///     .theme("ayu-mirage")
+///     .format(OutputFormat::Svg)
+///     .render("fn main() {}")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    options: RenderOptions,
+}
+
+impl Renderer {
+    pub fn new(language: impl Into<String>) -> Self {
+        let config = config::get();
+        let defaults = &config.defaults;
+        Self {
+            options: RenderOptions {
+                language: language.into(),
+                theme: defaults.theme.clone(),
+                format: OutputFormat::parse(&defaults.format)
+                    .unwrap_or(OutputFormat::Png),
+                line_numbers: true,
+                start_line: 1,
+                highlight_lines: LineRanges::default(),
+                dim: false,
+                lines: LineRanges::default(),
+                ellipsis: false,
+                tab_width: defaults.tab_width,
+                wrap: 0,
+                font_size: None,
+                padding: 0,
+                min_width: 0,
+                max_width: 0,
+                scale: 1.,
+                frame: Frame::default(),
+                font: config.assets.font_family.clone(),
+                font_features: FontFeatures::default(),
+                embed_font: false,
+                text_as_paths: false,
+                raster: RasterOptions::default(),
+                page_lines: 0,
+            },
+        }
+    }
+
+    pub fn from_options(options: RenderOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.options.language = language.into();
+        self
+    }
+
+    pub fn theme(mut self, theme: impl Into<String>) -> Self {
+        self.options.theme = theme.into();
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.options.format = format;
+        self
+    }
+
+    pub fn line_numbers(mut self, line_numbers: bool) -> Self {
+        self.options.line_numbers = line_numbers;
+        self
+    }
+
+    /// Number of the first line, for snippets taken out of a larger file
+    pub fn start_line(mut self, start_line: usize) -> Self {
+        self.options.start_line = start_line;
+        self
+    }
+
+    /// Draw attention to `lines`, optionally fading out the rest
+    pub fn highlight_lines(mut self, lines: LineRanges, dim: bool) -> Self {
+        self.options.highlight_lines = lines;
+        self.options.dim = dim;
+        self
+    }
+
+    /// Render only `lines` out of the source, optionally marking the rest
+    /// with `…`
+    pub fn lines(mut self, lines: LineRanges, ellipsis: bool) -> Self {
+        self.options.lines = lines;
+        self.options.ellipsis = ellipsis;
+        self
+    }
+
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.options.tab_width = tab_width;
+        self
+    }
+
+    /// Soft-wrap lines longer than `columns`, 0 disables wrapping
+    pub fn wrap(mut self, columns: usize) -> Self {
+        self.options.wrap = columns;
+        self
+    }
+
+    /// Keep `font_size` and size the picture to the content
+    pub fn font_size(mut self, font_size: usize) -> Self {
+        self.options.font_size = Some(font_size);
+        self
+    }
+
+    pub fn padding(mut self, padding: usize) -> Self {
+        self.options.padding = padding;
+        self
+    }
+
+    /// Bounds of the picture width with fixed font size, 0 for none
+    pub fn width_bounds(mut self, min_width: usize, max_width: usize) -> Self {
+        self.options.min_width = min_width;
+        self.options.max_width = max_width;
+        self
+    }
+
+    /// Zoom raster output, e.g. 2 for high density screens
+    pub fn scale(mut self, scale: f32) -> Self {
+        self.options.scale = scale;
+        self
+    }
+
+    /// Put the code into a window, for slides and social posts
+    pub fn frame(mut self, frame: Frame) -> Self {
+        self.options.frame = frame;
+        self
+    }
+
+    /// Font family, one of the fonts in `assets.fonts`
+    pub fn font(mut self, family: impl Into<String>) -> Self {
+        self.options.font = family.into();
+        self
+    }
+
+    /// Ligatures and other OpenType features of the font
+    pub fn font_features(mut self, features: FontFeatures) -> Self {
+        self.options.font_features = features;
+        self
+    }
+
+    /// Embed the font into SVG output, so that it doesn't depend on the
+    /// fonts installed where it's viewed
+    pub fn embed_font(mut self, embed_font: bool) -> Self {
+        self.options.embed_font = embed_font;
+        self
+    }
+
+    /// Convert text of SVG output to outlines, identical to PNG output
+    pub fn text_as_paths(mut self, text_as_paths: bool) -> Self {
+        self.options.text_as_paths = text_as_paths;
+        self
+    }
+
+    /// Quality and compression of raster output
+    pub fn raster(mut self, raster: RasterOptions) -> Self {
+        self.options.raster = raster;
+        self
+    }
+
+    /// Split PDF output into pages of `lines` rows, 0 for a single page
+    pub fn page_lines(mut self, lines: usize) -> Self {
+        self.options.page_lines = lines;
+        self
+    }
+
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
+    }
+
+    /// Highlight `source_code` and render it into the configured format
+    pub fn render(&self, source_code: &str) -> Result<Vec<u8>, Error> {
+        highlight(source_code, &self.options)
+    }
+}
+
+fn check_source_limits(
+    source_code: &str,
+    limits: &LimitsConfig,
+) -> Result<(), Error> {
+    let mut line_count = 0;
+    for (idx, line) in source_code.split('\n').enumerate() {
+        line_count += 1;
+        if limits.max_lines > 0 && line_count > limits.max_lines {
+            return Err(Error::TooManyLines {
+                max: limits.max_lines,
+            });
+        }
+        if limits.max_line_length > 0
+            && line.chars().count() > limits.max_line_length
+        {
+            return Err(Error::LineTooLong {
+                line: idx + 1,
+                max: limits.max_line_length,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn highlight_error(err: tree_sitter_highlight::Error) -> Error {
+    match err {
+        tree_sitter_highlight::Error::Cancelled => Error::Timeout,
+        err => Error::Highlight(err),
+    }
+}
+
+fn highlight(
+    source_code: &str,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, Error> {
+    let limits = &config::get().limits;
+    check_source_limits(source_code, limits)?;
+    let line_count = source_code.split('\n').count();
+    if !options.lines.is_empty()
+        && !(0..line_count)
+            .any(|idx| options.lines.contains(options.start_line + idx))
+    {
+        return Err(Error::InvalidParameter(
+            "lines",
+            options.lines.to_string(),
+        ));
+    }
+    let hl_cfg = highlight_config(&options.language)?;
+    let theme = Theme::load(&options.theme)
+        .ok_or_else(|| Error::UnknownTheme(options.theme.clone()))?;
+    if !fonts::has_family(&options.font) {
+        return Err(Error::UnknownFont(options.font.clone()));
+    }
+    let font_family = fonts::font_family_list(&options.font);
+    let font_features = options.font_features.css();
+    // Text of these is shaped by usvg rather than a browser
+    let is_outlined = options.format.is_raster() || options.text_as_paths;
+
+    let started_at = Instant::now();
+    let timeout = Duration::from_millis(limits.render_timeout_ms);
+    // Drawing and encoding can't be interrupted, so the budget is checked
+    // between steps
+    let check_timeout = || {
+        if limits.render_timeout_ms > 0 && started_at.elapsed() > timeout {
+            Err(Error::Timeout)
+        } else {
+            Ok(())
+        }
+    };
+    let mut highlighter = Highlighter::new();
+    if limits.render_timeout_ms > 0 {
+        highlighter
+            .parser()
+            .set_timeout_micros(limits.render_timeout_ms * 1000);
+    }
+    let attributes: Vec<_> = hl_cfg
+        .names()
+        .iter()
+        .map(|name| format!(r#"class="{}""#, name.replace(".", " ")))
+        .collect();
+    log::info!("Highlighting...");
+    let events = highlighter
+        .highlight(&hl_cfg, source_code.as_bytes(), None, |_| None)
+        .map_err(highlight_error)?
+        // Parsing is bounded by the parser timeout, this covers the rest
+        .map(|event| {
+            if limits.render_timeout_ms > 0 && started_at.elapsed() > timeout {
+                Err(tree_sitter_highlight::Error::Cancelled)
+            } else {
+                event
+            }
+        });
+
+    match options.format {
+        OutputFormat::Html => {
+            log::info!("Rendering HTML...");
+            let html = html_renderer::render(
+                events,
+                source_code,
+                &attributes,
+                &theme.html_stylesheet(&format!(
+                    "font-family:{};{}",
+                    font_family, font_features
+                )),
+                options,
+            )
+            .map_err(highlight_error)?;
+            return Ok(html.into_bytes());
+        }
+        OutputFormat::Ansi => {
+            log::info!("Rendering ANSI...");
+            let text = ansi_renderer::render(
+                events,
+                source_code,
+                hl_cfg.names(),
+                &theme,
+                options,
+            )
+            .map_err(highlight_error)?;
+            return Ok(text.into_bytes());
+        }
+        OutputFormat::Pdf => {
+            log::info!("Rendering PDF...");
+            return pdf_renderer::render(
+                events,
+                source_code,
+                hl_cfg.names(),
+                &theme,
+                options,
+            );
+        }
+        OutputFormat::Latex => {
+            log::info!("Rendering LaTeX...");
+            let latex = latex_renderer::render(
+                events,
+                source_code,
+                hl_cfg.names(),
+                &theme,
+                options,
+            )
+            .map_err(highlight_error)?;
+            return Ok(latex.into_bytes());
+        }
+        OutputFormat::Rtf => {
+            log::info!("Rendering RTF...");
+            let rtf = rtf_renderer::render(
+                events,
+                source_code,
+                hl_cfg.names(),
+                &theme,
+                options,
+            )
+            .map_err(highlight_error)?;
+            return Ok(rtf.into_bytes());
+        }
+        OutputFormat::Json => {
+            log::info!("Rendering JSON...");
+            return json_renderer::render(
+                events,
+                source_code,
+                hl_cfg.names(),
+                &theme,
+                options,
+            )
+            .map_err(highlight_error);
+        }
+        OutputFormat::LspSemanticTokens => {
+            log::info!("Rendering semantic tokens...");
+            return lsp_renderer::render(events, source_code, hl_cfg.names())
+                .map_err(highlight_error);
+        }
+        OutputFormat::Png
+        | OutputFormat::Jpeg
+        | OutputFormat::Webp
+        | OutputFormat::Svg => {}
+    }
+
+    log::info!("Creating renderer...");
+    let attribute_callback = |hl: &Highlight| attributes[hl.0].clone();
+    let defaults = &config::get().defaults;
+    let mut svg_renderer = SvgRenderer::new(source_code, &attribute_callback)
+        .with_picture_width(defaults.picture_width)
+        .with_font_family(font_family)
+        .with_font_features(font_features)
+        // usvg ignores `font-feature-settings`
+        .with_split_glyphs(
+            is_outlined && options.font_features.disables_ligatures(),
+        )
+        .with_font_metrics(metrics::font_metrics(&options.font))
+        .with_line_numbers(options.line_numbers)
+        .with_start_line(options.start_line)
+        .with_highlighted_lines(options.highlight_lines.clone(), options.dim)
+        .with_shown_lines(options.lines.clone(), options.ellipsis)
+        .with_wrap(options.wrap)
+        .with_tab_width(options.tab_width)
+        .with_padding(options.padding)
+        .with_frame(options.frame.clone());
+    if let Some(font_size) = options.font_size {
+        svg_renderer = svg_renderer
+            .with_fixed_font_size(font_size)
+            .with_width_bounds(options.min_width, options.max_width);
+    }
+    let mut stylesheet = theme.svg_stylesheet();
+    if options.embed_font && !is_outlined {
+        // Everything that can be drawn: the code, the gutter and the title
+        let chars = source_code
+            .chars()
+            .chain(options.frame.title.iter().flat_map(|title| title.chars()))
+            .chain("0123456789…↪".chars())
+            .filter(|c| !c.is_control())
+            .collect();
+        stylesheet =
+            fonts::embedded_font_faces(&options.font, &chars) + &stylesheet;
+    }
+    log::info!("Rendering SVG...");
+    svg_renderer
+        .render(events, stylesheet)
+        .map_err(highlight_error)?;
+    check_timeout()?;
+    if options.format == OutputFormat::Svg {
+        if !options.text_as_paths {
+            return Ok(svg_renderer.get_svg().as_bytes().to_vec());
+        }
+        // usvg converts text into paths while parsing
+        let tree =
+            usvg::Tree::from_str(&svg_renderer.get_svg(), &USVG_VECTOR_OPTIONS)
+                .map_err(Error::Svg)?;
+        return Ok(tree.to_string(usvg::XmlOptions::default()).into_bytes());
+    }
+
+    let tree =
+        usvg::Tree::from_str(&svg_renderer.get_svg(), &USVG_TREE_OPTIONS)
+            .map_err(Error::Svg)?;
+    let (width, height) = svg_renderer.get_picture_size();
+    let width = (width as f32 * options.scale).ceil() as usize;
+    let height = (height as f32 * options.scale).ceil() as usize;
+    if limits.max_pixels > 0 && width * height > limits.max_pixels {
+        return Err(Error::PictureTooLarge {
+            width,
+            height,
+            max_pixels: limits.max_pixels,
+        });
+    }
+    let mut pixmap = tiny_skia::Pixmap::new(width as u32, height as u32)
+        .ok_or_else(|| {
+            Error::Raster(format!("invalid picture size {}x{}", width, height))
+        })?;
+    if !options.format.has_alpha() {
+        // Transparent margins and corners would turn black otherwise
+        let background = options
+            .frame
+            .background
+            .as_deref()
+            .or_else(|| theme.global("background"))
+            .and_then(parse_color)
+            .unwrap_or([255, 255, 255, 255]);
+        let [r, g, b, _] = background;
+        pixmap.fill(tiny_skia::Color::from_rgba8(r, g, b, 255));
+    }
+    log::info!("Rendering {}...", options.format.extension());
+    check_timeout()?;
+    resvg::render(&tree, usvg::FitTo::Zoom(options.scale), pixmap.as_mut())
+        .ok_or_else(|| Error::Raster("resvg failed".to_string()))?;
+    check_timeout()?;
+    let image = raster::encode(&pixmap, options.format, &options.raster)
+        .map_err(Error::Raster)?;
+    check_timeout()?;
+    Ok(image)
+}
@@ -0,0 +1,164 @@
+//! RTF output for pasting into word processors, with colors of the theme in
+//! the color table. Every row is a paragraph shaded with the background, or
+//! the line highlight for highlighted lines
+
+use crate::line_ranges::Row;
+use crate::metrics::str_width;
+use crate::render::RenderOptions;
+use crate::styled_text::{styled_lines, Palette};
+use crate::theme::Theme;
+use crate::traversal;
+use crate::utils::expand_tabs;
+use tree_sitter_highlight::{Error, HighlightEvent};
+
+/// Font size when `font_size` isn't set, in points
+const DEFAULT_FONT_SIZE: usize = 10;
+
+/// Escape text for RTF, which is 7-bit: characters beyond ASCII are
+/// written as signed UTF-16 code units with `?` for readers without Unicode
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut units = [0; 2];
+    for c in text.chars() {
+        match c {
+            '\\' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\t' => escaped += "\\tab ",
+            c if c.is_ascii() => escaped.push(c),
+            c => {
+                for unit in c.encode_utf16(&mut units) {
+                    escaped += &format!("\\u{}?", *unit as i16);
+                }
+            }
+        }
+    }
+    escaped
+}
+
+/// Index of `color` in the color table, adding it on first use. Index 0 is
+/// the default color of the reader
+fn color_index(colors: &mut Vec<[u8; 3]>, color: [u8; 3]) -> usize {
+    match colors.iter().position(|&known| known == color) {
+        Some(idx) => idx + 1,
+        None => {
+            colors.push(color);
+            colors.len()
+        }
+    }
+}
+
+/// Render highlight events into an RTF document
+pub fn render<I>(
+    events: I,
+    source: &str,
+    names: &[String],
+    theme: &Theme,
+    options: &RenderOptions,
+) -> Result<String, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let lines = styled_lines(events, source, names, theme, options.tab_width)?;
+    let palette = Palette::new(theme);
+    let rows =
+        options
+            .lines
+            .rows(options.start_line, lines.len(), options.ellipsis);
+    let last_shown = rows.iter().rev().find_map(|row| match row {
+        Row::Line(idx) => Some(*idx),
+        Row::Elided => None,
+    });
+    let number_width = if options.line_numbers {
+        (options.start_line + last_shown.unwrap_or(0))
+            .to_string()
+            .len()
+    } else {
+        0
+    };
+    let code_width = traversal::lines(source)
+        .map(|line| str_width(&expand_tabs(line, options.tab_width)))
+        .max();
+    let font_size = options.font_size.unwrap_or(DEFAULT_FONT_SIZE);
+
+    let mut colors = Vec::new();
+    let background = color_index(&mut colors, palette.background);
+    let paragraph = |shading: usize| {
+        format!("\\pard\\plain\\f0\\fs{}\\cbpat{} ", font_size * 2, shading)
+    };
+    let mut body = String::with_capacity(source.len() * 2);
+    for row in rows {
+        let idx = match row {
+            Row::Line(idx) => idx,
+            Row::Elided => {
+                let gap = if options.ellipsis {
+                    format!("{:>width$}", "…", width = number_width)
+                } else {
+                    let width = number_width + 1 + code_width.unwrap_or(0);
+                    "┄".repeat(width)
+                };
+                body += &paragraph(background);
+                body += &format!(
+                    "{{\\cf{} {}}}\\par\n",
+                    color_index(&mut colors, palette.gutter),
+                    escape(&gap),
+                );
+                continue;
+            }
+        };
+        let line_number = options.start_line + idx;
+        let is_highlighted = options.highlight_lines.contains(line_number);
+        let shading = match palette.line_highlight.filter(|_| is_highlighted) {
+            Some(color) => color_index(&mut colors, color),
+            None => background,
+        };
+        let is_dimmed = options.dim && !is_highlighted;
+        let paint = |color: [u8; 3]| {
+            if is_dimmed {
+                palette.fade(color)
+            } else {
+                color
+            }
+        };
+        body += &paragraph(shading);
+        if options.line_numbers {
+            body += &format!(
+                "{{\\cf{} {:>width$} }}",
+                color_index(&mut colors, paint(palette.gutter)),
+                line_number,
+                width = number_width,
+            );
+        }
+        for (text, style) in &lines[idx] {
+            body += &format!(
+                "{{\\cf{}",
+                color_index(&mut colors, paint(palette.color(style)))
+            );
+            if style.bold {
+                body += "\\b";
+            }
+            if style.italic {
+                body += "\\i";
+            }
+            if style.underline {
+                body += "\\ul";
+            }
+            body += &format!(" {}}}", escape(text));
+        }
+        body += "\\par\n";
+    }
+
+    let mut rtf = format!(
+        "{{\\rtf1\\ansi\\deff0\\uc1\n{{\\fonttbl{{\\f0\\fmodern {};}}}}\n",
+        escape(&options.font)
+    );
+    rtf += "{\\colortbl;";
+    for [r, g, b] in colors {
+        rtf += &format!("\\red{}\\green{}\\blue{};", r, g, b);
+    }
+    rtf += "}\n";
+    rtf += &body;
+    rtf += "}\n";
+    Ok(rtf)
+}
@@ -0,0 +1,379 @@
+use crate::config::SnippetsConfig;
+use lru::LruCache;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, fs, io,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Length of snippet IDs, in base64url characters
+pub const ID_LENGTH: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snippet {
+    pub language: String,
+    pub code: String,
+    /// Unix timestamps, in seconds
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+    UnknownKind(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "snippet store I/O error: {}", err),
+            Self::Json(err) => write!(f, "malformed snippet: {}", err),
+            Self::Sqlite(err) => write!(f, "snippet store error: {}", err),
+            Self::UnknownKind(kind) => {
+                write!(f, "unknown snippet store `{}`", kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Sqlite(err)
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Snippet {
+    pub fn new(language: String, code: String, ttl: Option<u64>) -> Self {
+        let created_at = now();
+        Self {
+            language,
+            code,
+            created_at,
+            expires_at: ttl.map(|ttl| created_at + ttl),
+        }
+    }
+
+    /// Snippets are addressed by content, so saving the same code twice
+    /// yields the same ID
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.language.as_bytes());
+        hasher.update(&[0u8]);
+        hasher.update(self.code.as_bytes());
+        let mut id =
+            base64::encode_config(hasher.finalize(), base64::URL_SAFE_NO_PAD);
+        id.truncate(ID_LENGTH);
+        id
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+
+    /// Combine with the unexpired snippet already saved under the same ID,
+    /// so that saving it again never shortens its lifetime. The later expiry
+    /// is kept, `None` being later than any
+    pub fn merge(&self, existing: Option<Snippet>) -> Snippet {
+        let mut snippet = self.clone();
+        if let Some(existing) = existing {
+            snippet.created_at = existing.created_at.min(snippet.created_at);
+            snippet.expires_at = match (existing.expires_at, self.expires_at) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            };
+        }
+        snippet
+    }
+}
+
+/// Check that the ID could have been produced by [`Snippet::id`], so that
+/// it's safe to use as a file name
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == ID_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Storage backend for snippets. Expired snippets must not be returned
+pub trait SnippetStore: Send + Sync {
+    /// Save the snippet, [merged](Snippet::merge) with the one already saved
+    /// under `id`. Returns the snippet as saved
+    fn put(&self, id: &str, snippet: &Snippet) -> Result<Snippet, StoreError>;
+    fn get(&self, id: &str) -> Result<Option<Snippet>, StoreError>;
+    /// Remove expired snippets, returning how many were removed
+    fn purge_expired(&self) -> Result<usize, StoreError>;
+}
+
+/// Keeps snippets in memory, removing the least recently used ones once
+/// they take more than `max_bytes`
+pub struct MemoryStore {
+    snippets: Mutex<MemorySnippets>,
+    max_bytes: usize,
+}
+
+struct MemorySnippets {
+    by_id: LruCache<String, Snippet>,
+    /// Sum of [`memory_size`] of the snippets
+    bytes: usize,
+}
+
+/// Approximate memory taken by a snippet, not counting fixed-size fields
+fn memory_size(id: &str, snippet: &Snippet) -> usize {
+    id.len() + snippet.language.len() + snippet.code.len()
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            snippets: Mutex::new(MemorySnippets {
+                by_id: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            max_bytes: 0,
+        }
+    }
+}
+
+impl MemoryStore {
+    /// Limit the size of the snippets, 0 is unlimited. The snippet saved
+    /// last is always kept
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+}
+
+impl SnippetStore for MemoryStore {
+    fn put(&self, id: &str, snippet: &Snippet) -> Result<Snippet, StoreError> {
+        let mut snippets = self.snippets.lock().unwrap();
+        let existing = snippets.by_id.get(id).filter(|s| !s.is_expired(now()));
+        let snippet = snippet.merge(existing.cloned());
+        snippets.bytes += memory_size(id, &snippet);
+        if let Some(old) = snippets.by_id.put(id.to_string(), snippet.clone()) {
+            snippets.bytes -= memory_size(id, &old);
+        }
+        while self.max_bytes > 0
+            && snippets.bytes > self.max_bytes
+            && snippets.by_id.len() > 1
+        {
+            if let Some((id, old)) = snippets.by_id.pop_lru() {
+                snippets.bytes -= memory_size(&id, &old);
+            }
+        }
+        Ok(snippet)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Snippet>, StoreError> {
+        let mut snippets = self.snippets.lock().unwrap();
+        Ok(snippets
+            .by_id
+            .get(id)
+            .filter(|s| !s.is_expired(now()))
+            .cloned())
+    }
+
+    fn purge_expired(&self) -> Result<usize, StoreError> {
+        let mut snippets = self.snippets.lock().unwrap();
+        let now = now();
+        let expired: Vec<_> = snippets
+            .by_id
+            .iter()
+            .filter(|(_, snippet)| snippet.is_expired(now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            if let Some(old) = snippets.by_id.pop(id) {
+                snippets.bytes -= memory_size(id, &old);
+            }
+        }
+        Ok(expired.len())
+    }
+}
+
+/// Keeps every snippet in a JSON file named after its ID
+pub struct DirStore {
+    dir: PathBuf,
+    /// Held while merging with a saved snippet and writing the result
+    write_lock: Mutex<()>,
+}
+
+impl DirStore {
+    pub fn open(dir: PathBuf) -> Result<Self, StoreError> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+impl SnippetStore for DirStore {
+    fn put(&self, id: &str, snippet: &Snippet) -> Result<Snippet, StoreError> {
+        let _guard = self.write_lock.lock().unwrap();
+        // A malformed file is replaced rather than merged
+        let snippet = snippet.merge(self.get(id).ok().flatten());
+        let tmp_path = self.dir.join(format!("{}.json.tmp", id));
+        fs::write(&tmp_path, serde_json::to_vec(&snippet)?)?;
+        fs::rename(&tmp_path, self.path(id))?;
+        Ok(snippet)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Snippet>, StoreError> {
+        let raw = match fs::read(self.path(id)) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let snippet: Snippet = serde_json::from_slice(&raw)?;
+        Ok(Some(snippet).filter(|s| !s.is_expired(now())))
+    }
+
+    fn purge_expired(&self) -> Result<usize, StoreError> {
+        let now = now();
+        let mut count = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            let is_expired = fs::read(&path)
+                .ok()
+                .and_then(|raw| serde_json::from_slice::<Snippet>(&raw).ok())
+                .map_or(false, |snippet| snippet.is_expired(now));
+            if is_expired {
+                fs::remove_file(&path)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Unexpired snippet saved under `id` in the database
+fn query_snippet(
+    connection: &Connection,
+    id: &str,
+) -> Result<Option<Snippet>, StoreError> {
+    let snippet = connection
+        .query_row(
+            "SELECT language, code, created_at, expires_at
+            FROM snippets WHERE id = ?1",
+            params![id],
+            |row| {
+                Ok(Snippet {
+                    language: row.get(0)?,
+                    code: row.get(1)?,
+                    created_at: row.get::<_, i64>(2)? as u64,
+                    expires_at: row.get::<_, Option<i64>>(3)?.map(|t| t as u64),
+                })
+            },
+        )
+        .optional()?;
+    Ok(snippet.filter(|s| !s.is_expired(now())))
+}
+
+/// Keeps snippets in an SQLite database file
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: PathBuf) -> Result<Self, StoreError> {
+        let connection = Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS snippets (
+                id TEXT PRIMARY KEY,
+                language TEXT NOT NULL,
+                code TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER
+            )",
+            [],
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl SnippetStore for SqliteStore {
+    fn put(&self, id: &str, snippet: &Snippet) -> Result<Snippet, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let snippet = snippet.merge(query_snippet(&connection, id)?);
+        connection.execute(
+            "INSERT OR REPLACE INTO snippets
+                (id, language, code, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                id,
+                snippet.language,
+                snippet.code,
+                snippet.created_at as i64,
+                snippet.expires_at.map(|t| t as i64),
+            ],
+        )?;
+        Ok(snippet)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Snippet>, StoreError> {
+        query_snippet(&self.connection.lock().unwrap(), id)
+    }
+
+    fn purge_expired(&self) -> Result<usize, StoreError> {
+        let count = self.connection.lock().unwrap().execute(
+            "DELETE FROM snippets WHERE expires_at <= ?1",
+            params![now() as i64],
+        )?;
+        Ok(count)
+    }
+}
+
+/// Open the store selected by `snippets.store` in the config
+pub fn open_store(
+    config: &SnippetsConfig,
+) -> Result<Box<dyn SnippetStore>, StoreError> {
+    let store: Box<dyn SnippetStore> = match config.store.as_str() {
+        "memory" => Box::new(
+            MemoryStore::default().with_max_bytes(config.max_memory_bytes),
+        ),
+        "dir" => Box::new(DirStore::open(config.path.clone())?),
+        "sqlite" => Box::new(SqliteStore::open(config.path.clone())?),
+        kind => return Err(StoreError::UnknownKind(kind.to_string())),
+    };
+    Ok(store)
+}
@@ -0,0 +1,143 @@
+//! Highlighted text split into lines of tokens, for renderers that don't
+//! build on SVG. Lines are the ones of [`traversal::walk`]
+
+use crate::theme::{opaque_color, parse_color, Style, Theme};
+use crate::traversal::{self, Visitor};
+use crate::utils::TabExpander;
+use std::ops::Range;
+use tree_sitter_highlight::{Error, Highlight, HighlightEvent};
+
+/// Source text between highlight boundaries, within a line
+#[derive(Debug, Clone)]
+pub struct Token {
+    /// Byte range in the source
+    pub range: Range<usize>,
+    /// Highlights the text is nested in, outermost first
+    pub highlights: Vec<Highlight>,
+}
+
+/// Collects tokens for [`token_lines`]
+struct TokenCollector {
+    lines: Vec<Vec<Token>>,
+    hl_stack: Vec<Highlight>,
+}
+
+impl Visitor for TokenCollector {
+    fn start_highlight(&mut self, highlight: Highlight) {
+        self.hl_stack.push(highlight);
+    }
+
+    fn end_highlight(&mut self) {
+        self.hl_stack.pop();
+    }
+
+    fn text(&mut self, range: Range<usize>) {
+        let line = self.lines.last_mut().expect("Not empty");
+        line.push(Token {
+            range,
+            highlights: self.hl_stack.clone(),
+        });
+    }
+
+    fn line_break(&mut self) {
+        self.lines.push(Vec::new());
+    }
+}
+
+/// Split highlighted source into lines of tokens, leaving out line breaks
+pub fn token_lines<I>(events: I, source: &str) -> Result<Vec<Vec<Token>>, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let mut collector = TokenCollector {
+        lines: vec![Vec::new()],
+        hl_stack: Vec::new(),
+    };
+    traversal::walk(events, source, &mut collector)?;
+    Ok(collector.lines)
+}
+
+/// Runs of text sharing a style
+pub type StyledLine = Vec<(String, Style)>;
+
+/// Split highlighted source into lines of runs, with styles of nested
+/// highlights merged and tabs expanded
+pub fn styled_lines<I>(
+    events: I,
+    source: &str,
+    names: &[String],
+    theme: &Theme,
+    tab_width: usize,
+) -> Result<Vec<StyledLine>, Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    let styles: Vec<_> =
+        names.iter().map(|name| theme.style_for(name)).collect();
+    let lines = token_lines(events, source)?;
+    Ok(lines
+        .into_iter()
+        .map(|line| {
+            let mut tabs = TabExpander::new(tab_width);
+            line.into_iter()
+                .map(|token| {
+                    let mut style = Style::default();
+                    for hl in &token.highlights {
+                        style.merge(&styles[hl.0]);
+                    }
+                    (tabs.expand(&source[token.range]).into_owned(), style)
+                })
+                .collect()
+        })
+        .collect())
+}
+
+/// Opaque colors of the theme
+#[derive(Debug, Clone)]
+pub struct Palette {
+    theme_background: Option<[u8; 4]>,
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+    pub gutter: [u8; 3],
+    pub line_highlight: Option<[u8; 3]>,
+}
+
+impl Palette {
+    pub fn new(theme: &Theme) -> Self {
+        let theme_background = theme.global("background").and_then(parse_color);
+        let resolve = |color: Option<&str>| {
+            color.and_then(|color| opaque_color(color, theme_background))
+        };
+        let foreground =
+            resolve(theme.global("foreground")).unwrap_or([0, 0, 0]);
+        Self {
+            theme_background,
+            background: resolve(theme.global("background"))
+                .unwrap_or([255, 255, 255]),
+            foreground,
+            gutter: resolve(theme.gutter_foreground()).unwrap_or(foreground),
+            line_highlight: resolve(theme.line_highlight()),
+        }
+    }
+
+    /// Color of text in `style`
+    pub fn color(&self, style: &Style) -> [u8; 3] {
+        style
+            .color
+            .as_deref()
+            .and_then(|color| opaque_color(color, self.theme_background))
+            .unwrap_or(self.foreground)
+    }
+
+    /// Mix 40% of `color` into the background, like `.dimmed` in
+    /// stylesheets
+    pub fn fade(&self, color: [u8; 3]) -> [u8; 3] {
+        let mut faded = [0; 3];
+        for (idx, channel) in faded.iter_mut().enumerate() {
+            *channel = (color[idx] as f64 * 0.4
+                + self.background[idx] as f64 * 0.6)
+                as u8;
+        }
+        faded
+    }
+}
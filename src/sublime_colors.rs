@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::fmt;
 
 use crate::stylesheet::Stylesheet;
+use crate::theme::{self, Style, Theme};
 
 #[derive(Deserialize, Debug)]
 pub struct SublimeColorScheme {
@@ -143,6 +144,30 @@ impl SublimeColorScheme {
         decl.push('}');
         selector + &decl
     }
+
+    /// Convert into a [`Theme`], which serves every output format
+    pub fn to_theme(&self) -> Theme {
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| theme::Rule {
+                selectors: rule
+                    .scope
+                    .0
+                    .iter()
+                    .map(|scope| scope.split('.').map(String::from).collect())
+                    .collect(),
+                style: Style {
+                    color: rule.foreground.as_ref().map(|c| c.0.clone()),
+                    background: rule.background.as_ref().map(|c| c.0.clone()),
+                    bold: rule.font_style.bold,
+                    italic: rule.font_style.italic,
+                    underline: rule.font_style.underline,
+                },
+            })
+            .collect();
+        Theme::new(self.globals.clone(), rules)
+    }
 }
 
 impl Stylesheet for SublimeColorScheme {
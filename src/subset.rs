@@ -0,0 +1,228 @@
+//! Subsetting of TrueType fonts for embedding. Outlines of glyphs that
+//! aren't needed are dropped while glyph IDs stay the same, so that every
+//! table other than `glyf` and `loca` is copied as is. Glyphs without a
+//! character of their own, like ligatures and stylistic alternates, are
+//! kept, since substitutions can still produce them.
+
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+struct Table<'a> {
+    tag: [u8; 4],
+    data: &'a [u8],
+}
+
+/// Tables of the face at `index`, which is only non-zero in collections
+fn read_tables(data: &[u8], index: u32) -> Option<(u32, Vec<Table>)> {
+    let start = if data.get(0..4)? == b"ttcf" {
+        read_u32(data, 12 + 4 * index as usize)? as usize
+    } else {
+        0
+    };
+    let sfnt_version = read_u32(data, start)?;
+    let num_tables = read_u16(data, start + 4)? as usize;
+    let tables = (0..num_tables)
+        .map(|idx| {
+            let record = start + 12 + 16 * idx;
+            let offset = read_u32(data, record + 8)? as usize;
+            let length = read_u32(data, record + 12)? as usize;
+            Some(Table {
+                tag: data.get(record..record + 4)?.try_into().ok()?,
+                data: data.get(offset..offset + length)?,
+            })
+        })
+        .collect::<Option<_>>()?;
+    Some((sfnt_version, tables))
+}
+
+fn find<'a>(tables: &[Table<'a>], tag: &[u8; 4]) -> Option<&'a [u8]> {
+    tables
+        .iter()
+        .find(|table| &table.tag == tag)
+        .map(|table| table.data)
+}
+
+/// Glyphs used by a composite glyph
+fn components(glyph: &[u8]) -> Vec<u16> {
+    const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+    const WE_HAVE_A_SCALE: u16 = 0x0008;
+    const MORE_COMPONENTS: u16 = 0x0020;
+    const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+    const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+
+    let mut glyphs = Vec::new();
+    let is_composite = read_u16(glyph, 0).map_or(false, |n| (n as i16) < 0);
+    if !is_composite {
+        return glyphs;
+    }
+    let mut offset = 10;
+    while let (Some(flags), Some(glyph_id)) =
+        (read_u16(glyph, offset), read_u16(glyph, offset + 2))
+    {
+        glyphs.push(glyph_id);
+        offset += 4;
+        offset += if flags & ARG_1_AND_2_ARE_WORDS != 0 {
+            4
+        } else {
+            2
+        };
+        if flags & WE_HAVE_A_SCALE != 0 {
+            offset += 2;
+        } else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+            offset += 4;
+        } else if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+            offset += 8;
+        }
+        if flags & MORE_COMPONENTS == 0 {
+            break;
+        }
+    }
+    glyphs
+}
+
+/// Glyphs needed to draw `chars`, plus the ones without a character
+fn glyphs_to_keep(
+    data: &[u8],
+    index: u32,
+    chars: &BTreeSet<char>,
+) -> Option<BTreeSet<u16>> {
+    let face = ttf_parser::Face::from_slice(data, index).ok()?;
+    let mut encoded = BTreeSet::new();
+    for subtable in face.character_mapping_subtables() {
+        if subtable.is_unicode() {
+            subtable.codepoints(|code| {
+                if let Some(glyph) = subtable.glyph_index(code) {
+                    encoded.insert(glyph.0);
+                }
+            });
+        }
+    }
+    let mut keep: BTreeSet<u16> = (0..face.number_of_glyphs())
+        .filter(|glyph| !encoded.contains(glyph))
+        .collect();
+    keep.extend(
+        chars
+            .iter()
+            .filter_map(|&c| face.glyph_index(c))
+            .map(|glyph| glyph.0),
+    );
+    Some(keep)
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+/// Assemble a font file out of tables, sorted by tag
+fn write_font(sfnt_version: u32, tables: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let num_tables = tables.len() as u16;
+    let entry_selector = 15 - num_tables.leading_zeros() as u16;
+    let search_range = (1 << entry_selector) * 16;
+    let mut font = Vec::new();
+    font.extend_from_slice(&sfnt_version.to_be_bytes());
+    font.extend_from_slice(&num_tables.to_be_bytes());
+    font.extend_from_slice(&search_range.to_be_bytes());
+    font.extend_from_slice(&entry_selector.to_be_bytes());
+    font.extend_from_slice(&(num_tables * 16 - search_range).to_be_bytes());
+    let mut offset = 12 + 16 * tables.len();
+    for (tag, data) in tables {
+        font.extend_from_slice(&tag[..]);
+        font.extend_from_slice(&checksum(data).to_be_bytes());
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        offset += (data.len() + 3) & !3;
+    }
+    let mut head_offset = None;
+    for (tag, data) in tables {
+        if *tag == b"head" {
+            head_offset = Some(font.len());
+        }
+        font.extend_from_slice(data);
+        font.resize((font.len() + 3) & !3, 0);
+    }
+    if let Some(head) = head_offset {
+        let adjustment = 0xB1B0_AFBAu32.wrapping_sub(checksum(&font));
+        font[head + 8..head + 12].copy_from_slice(&adjustment.to_be_bytes());
+    }
+    font
+}
+
+/// Standalone TrueType font with outlines only for the glyphs of `chars`,
+/// `None` if the face has no TrueType outlines
+pub fn subset(
+    data: &[u8],
+    index: u32,
+    chars: &BTreeSet<char>,
+) -> Option<Vec<u8>> {
+    let (sfnt_version, tables) = read_tables(data, index)?;
+    let head = find(&tables, b"head").filter(|head| head.len() >= 54)?;
+    let loca = find(&tables, b"loca")?;
+    let glyf = find(&tables, b"glyf")?;
+    let num_glyphs = read_u16(find(&tables, b"maxp")?, 4)? as usize;
+    let is_long_loca = read_u16(head, 50)? == 1;
+    let glyph_range = |glyph: usize| {
+        let (start, end) = if is_long_loca {
+            (read_u32(loca, glyph * 4)?, read_u32(loca, glyph * 4 + 4)?)
+        } else {
+            let start = read_u16(loca, glyph * 2)? as u32 * 2;
+            (start, read_u16(loca, glyph * 2 + 2)? as u32 * 2)
+        };
+        glyf.get(start as usize..end as usize)
+    };
+
+    let mut keep = glyphs_to_keep(data, index, chars)?;
+    keep.insert(0);
+    let mut pending: Vec<_> = keep.iter().copied().collect();
+    while let Some(glyph) = pending.pop() {
+        let outline = glyph_range(glyph as usize).unwrap_or_default();
+        for component in components(outline) {
+            if keep.insert(component) {
+                pending.push(component);
+            }
+        }
+    }
+
+    let mut new_glyf = Vec::new();
+    let mut new_loca = Vec::with_capacity((num_glyphs + 1) * 4);
+    for glyph in 0..num_glyphs {
+        new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+        if keep.contains(&(glyph as u16)) {
+            new_glyf.extend_from_slice(glyph_range(glyph)?);
+            new_glyf.resize((new_glyf.len() + 3) & !3, 0);
+        }
+    }
+    new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+    let mut new_head = head.to_vec();
+    new_head[8..12].copy_from_slice(&[0; 4]);
+    new_head[50..52].copy_from_slice(&1u16.to_be_bytes());
+
+    let mut new_tables: Vec<(&[u8; 4], &[u8])> = tables
+        .iter()
+        // The signature doesn't match the changed font
+        .filter(|table| &table.tag != b"DSIG")
+        .map(|table| match &table.tag {
+            b"head" => (&table.tag, &new_head[..]),
+            b"loca" => (&table.tag, &new_loca[..]),
+            b"glyf" => (&table.tag, &new_glyf[..]),
+            _ => (&table.tag, table.data),
+        })
+        .collect();
+    new_tables.sort_by_key(|(tag, _)| **tag);
+    Some(write_font(sfnt_version, &new_tables))
+}
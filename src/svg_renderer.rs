@@ -1,5 +1,12 @@
+use crate::frame::Frame;
+use crate::line_ranges::{LineRanges, Row};
+use crate::metrics::{grapheme_width, str_width, FontMetrics};
+use crate::traversal::{self, Visitor};
+use crate::utils::{expand_tabs, TabExpander};
 use htmlescape::encode_minimal;
+use std::ops::Range;
 use tree_sitter_highlight::{Error, Highlight, HighlightEvent};
+use unicode_segmentation::UnicodeSegmentation;
 
 pub struct SvgRenderer<'a, AttrFn> {
     hl_stack: Vec<Highlight>,
@@ -10,9 +17,47 @@ pub struct SvgRenderer<'a, AttrFn> {
     max_line_width: usize,
     line_count: usize,
     font_size: usize,
+    /// Font size that is kept regardless of line widths, sizing the picture
+    /// to the content instead
+    fixed_font_size: Option<usize>,
     picture_width: usize,
+    min_width: usize,
+    max_width: usize,
+    padding: usize,
+    frame: Frame,
+    /// Groups opened by the prologue, closed by the epilogue
+    open_groups: usize,
     number_column_width: usize,
+    /// Value of the `font-family` property
+    font_family: String,
+    /// CSS declarations of OpenType features
+    font_features: String,
+    /// Position every grapheme on its own so that no ligatures are formed
+    split_glyphs: bool,
     font_aspect_ratio: f32,
+    ascender: f32,
+    descender: f32,
+    start_line: usize,
+    line_numbers: bool,
+    highlighted_lines: LineRanges,
+    dim: bool,
+    shown_lines: LineRanges,
+    ellipsis: bool,
+    wrap: usize,
+    tab_width: usize,
+    /// Tabs of the current line, expanded as they're drawn
+    tabs: TabExpander,
+    rows: Vec<Row>,
+    /// Index in `rows` of the next shown line or marker
+    next_row: usize,
+    /// Rows output so far, including continuation rows of wrapped lines
+    current_row: usize,
+    /// Columns taken by the current row
+    column: usize,
+    is_highlighted: bool,
+    /// Where output of the current line starts, to drop it if it's hidden
+    line_start: usize,
+    is_hidden: bool,
 }
 
 impl<'a, AttrFn> SvgRenderer<'a, AttrFn>
@@ -20,7 +65,7 @@ where
     AttrFn: Fn(&Highlight) -> String,
 {
     pub fn new(source: &'a str, attr_callback: AttrFn) -> Self {
-        let mut result = Self {
+        Self {
             svg: String::with_capacity(10 * 1024),
             source,
             hl_stack: Vec::new(),
@@ -29,20 +74,199 @@ where
             max_line_width: 0,
             line_count: 0,
             font_size: 20,
+            fixed_font_size: None,
             picture_width: 512 << 1,
-            number_column_width: 4,
+            min_width: 0,
+            max_width: 0,
+            padding: 0,
+            frame: Frame::default(),
+            open_groups: 0,
+            number_column_width: 0,
+            font_family: "monospace".to_string(),
+            font_features: String::new(),
+            split_glyphs: false,
             font_aspect_ratio: 3. / 5.,
-        };
-        result.calculate_max_line_width();
-        result
+            ascender: 0.8,
+            descender: 0.2,
+            start_line: 1,
+            line_numbers: false,
+            highlighted_lines: LineRanges::default(),
+            dim: false,
+            shown_lines: LineRanges::default(),
+            ellipsis: false,
+            wrap: 0,
+            tab_width: 4,
+            tabs: TabExpander::new(4),
+            rows: Vec::new(),
+            next_row: 0,
+            current_row: 0,
+            column: 0,
+            is_highlighted: false,
+            line_start: 0,
+            is_hidden: false,
+        }
+    }
+
+    /// Show line numbers in a gutter wide enough to fit the largest one
+    pub fn with_line_numbers(mut self, line_numbers: bool) -> Self {
+        self.line_numbers = line_numbers;
+        self
+    }
+
+    /// Number of the first line, which line numbers and highlighted lines
+    /// are counted from
+    pub fn with_start_line(mut self, start_line: usize) -> Self {
+        self.start_line = start_line;
+        self
+    }
+
+    /// Paint a background band behind `lines`. With `dim`, every other line
+    /// is faded out
+    pub fn with_highlighted_lines(
+        mut self,
+        lines: LineRanges,
+        dim: bool,
+    ) -> Self {
+        self.highlighted_lines = lines;
+        self.dim = dim;
+        self
+    }
+
+    /// Render only `lines`, counted from the start line, while the whole
+    /// source is still highlighted. Gaps between them are marked with
+    /// a separator, or with `…` if `ellipsis` is set, which also marks lines
+    /// left out at the start and at the end
+    pub fn with_shown_lines(
+        mut self,
+        lines: LineRanges,
+        ellipsis: bool,
+    ) -> Self {
+        self.shown_lines = lines;
+        self.ellipsis = ellipsis;
+        self
+    }
+
+    /// Wrap lines longer than `columns` onto continuation rows, which have
+    /// a marker in the gutter instead of a line number. 0 disables wrapping
+    pub fn with_wrap(mut self, columns: usize) -> Self {
+        self.wrap = columns;
+        self
+    }
+
+    /// Columns between tab stops
+    pub fn with_tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = tab_width;
+        self.tabs = TabExpander::new(tab_width);
+        self
+    }
+
+    pub fn with_picture_width(mut self, picture_width: usize) -> Self {
+        self.picture_width = picture_width;
+        self
+    }
+
+    /// Keep font size fixed and make the picture as wide as the content,
+    /// instead of fitting the content into the picture width
+    pub fn with_fixed_font_size(mut self, font_size: usize) -> Self {
+        self.fixed_font_size = Some(font_size);
+        self
+    }
+
+    /// Bounds of the picture width with fixed font size, 0 for none. Font
+    /// is shrunk if content doesn't fit into `max_width`
+    pub fn with_width_bounds(
+        mut self,
+        min_width: usize,
+        max_width: usize,
+    ) -> Self {
+        self.min_width = min_width;
+        self.max_width = max_width;
+        self
+    }
+
+    /// Space around the content, in pixels
+    pub fn with_padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Put the code into a window on an outer background
+    pub fn with_frame(mut self, frame: Frame) -> Self {
+        self.frame = frame;
+        self
     }
 
-    fn calculate_max_line_width(&mut self) {
-        let lines = self.source.split('\n');
-        self.max_line_width =
-            lines.clone().map(|line| line.len()).max().unwrap()
-                + self.number_column_width;
-        self.line_count = lines.count();
+    pub fn with_font_family(mut self, font_family: String) -> Self {
+        self.font_family = font_family;
+        self
+    }
+
+    pub fn with_font_features(mut self, font_features: String) -> Self {
+        self.font_features = font_features;
+        self
+    }
+
+    /// Prevent ligatures where `font_features` can't, e.g. in usvg
+    pub fn with_split_glyphs(mut self, split_glyphs: bool) -> Self {
+        self.split_glyphs = split_glyphs;
+        self
+    }
+
+    /// Lay out text with metrics of the font it's going to be rendered with
+    pub fn with_font_metrics(mut self, metrics: FontMetrics) -> Self {
+        self.font_aspect_ratio = metrics.advance;
+        self.ascender = metrics.ascender;
+        self.descender = metrics.descender;
+        self
+    }
+
+    /// Decide which rows lines go to, and measure the shown ones
+    fn layout(&mut self) {
+        let lines: Vec<_> = traversal::lines(self.source)
+            .map(|line| expand_tabs(line, self.tab_width))
+            .collect();
+        self.rows =
+            self.shown_lines
+                .rows(self.start_line, lines.len(), self.ellipsis);
+        let shown: Vec<_> = self
+            .rows
+            .iter()
+            .filter_map(|row| match row {
+                Row::Line(idx) => Some(*idx),
+                Row::Elided => None,
+            })
+            .collect();
+        self.max_line_width = shown
+            .iter()
+            .map(|&idx| str_width(&lines[idx]))
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut continuation_rows = 0;
+        if self.wrap > 0 {
+            self.max_line_width = self.max_line_width.min(self.wrap);
+            continuation_rows = shown
+                .iter()
+                .map(|&idx| wrapped_rows(&lines[idx], self.wrap) - 1)
+                .sum();
+        }
+        self.line_count = (self.rows.len() + continuation_rows).max(1);
+        if self.line_numbers {
+            let last_line =
+                self.start_line + shown.last().copied().unwrap_or(0);
+            self.number_column_width = last_line.to_string().len() + 1;
+        } else if self.wrap > 0 {
+            // Room for continuation markers
+            self.number_column_width = 2;
+        }
+    }
+
+    fn total_columns(&self) -> usize {
+        self.max_line_width + self.number_column_width
+    }
+
+    fn char_width(&self) -> f32 {
+        self.font_size as f32 * self.font_aspect_ratio
     }
 
     /// Renger highlight events to svg string
@@ -55,58 +279,105 @@ where
         I: Iterator<Item = Result<HighlightEvent, Error>>,
     {
         self.prologue(stylesheet);
-        for event in events {
-            match event {
-                Ok(HighlightEvent::HighlightStart(hl)) => {
-                    self.start_highligt(&hl)
-                }
-                Ok(HighlightEvent::Source { start, end }) => {
-                    self.highlight_source(start, end);
-                }
-                Ok(HighlightEvent::HighlightEnd) => {
-                    self.end_highlight();
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-        }
+        traversal::walk(events, self.source, self)?;
         self.epilogue();
         Ok(())
     }
 
-    fn start_highligt(&mut self, hl: &Highlight) {
-        self.hl_stack.push(hl.clone());
-        self.new_tspan(&hl);
+    fn close_text(&mut self) {
+        self.svg += &"</tspan>".repeat(self.hl_stack.len());
+        self.svg += "</text>\n";
     }
 
-    fn highlight_source(&mut self, start: usize, end: usize) {
-        let chunk = encode_minimal(&self.source[start..end].to_owned());
-        for c in chunk.chars() {
-            if c == '\n' {
-                self.svg += &"</tspan>".repeat(self.hl_stack.len());
-                self.svg += &"</text>\n";
-                self.new_text();
-                for hl in self.hl_stack.clone().iter() {
-                    self.new_tspan(&hl);
-                }
-                continue;
-            }
-            self.svg.push(c);
+    fn reopen_tspans(&mut self) {
+        for hl in self.hl_stack.clone().iter() {
+            self.new_tspan(&hl);
         }
     }
 
-    fn end_highlight(&mut self) {
-        self.svg += "</tspan>";
-        self.hl_stack.pop();
+    /// Output markers for elided lines that come next
+    fn elided_rows(&mut self) {
+        while self.rows.get(self.next_row) == Some(&Row::Elided) {
+            self.next_row += 1;
+            self.current_row += 1;
+            if self.ellipsis {
+                self.svg += &format!(
+                    r#"<text y="{}em" class="gutter elided" xml:space="preserve">{:>width$}</text>"#,
+                    self.current_row,
+                    "…",
+                    width = self.number_column_width.saturating_sub(1),
+                );
+            } else {
+                let y =
+                    (self.current_row as f32 - 0.35) * self.font_size as f32;
+                self.svg += &format!(
+                    r#"<line x1="0" x2="{}" y1="{y}" y2="{y}" class="separator" />"#,
+                    self.content_width(),
+                    y = y,
+                );
+            }
+            self.svg.push('\n');
+        }
     }
 
     fn new_text(&mut self) {
         self.current_line += 1;
+        self.elided_rows();
+        self.line_start = self.svg.len();
+        let idx = self.current_line as usize - 1;
+        self.is_hidden = self.rows.get(self.next_row) != Some(&Row::Line(idx));
+        if self.is_hidden {
+            self.svg += "<text>";
+            return;
+        }
+        self.next_row += 1;
+        let line_number = self.start_line + idx;
+        self.is_highlighted = self.highlighted_lines.contains(line_number);
+        self.start_row(Some(line_number));
+    }
+
+    /// Open a row of the current line, `line_number` being `None` for
+    /// continuation rows
+    fn start_row(&mut self, line_number: Option<usize>) {
+        self.current_row += 1;
+        self.column = 0;
+        let row = self.current_row;
+        if self.is_highlighted {
+            // Center the band on the glyph box rather than the baseline
+            let center = row as f32 - (self.ascender - self.descender) / 2.;
+            self.svg += &format!(
+                r#"<rect x="-{}" y="{}" width="{}" height="{}" class="line-highlight" />"#,
+                self.padding,
+                (center - 0.5) * self.font_size as f32,
+                self.window_width(),
+                self.font_size,
+            );
+        }
+        let dimmed = if self.dim && !self.is_highlighted {
+            " dimmed"
+        } else {
+            ""
+        };
+        let (gutter_class, gutter) = match line_number {
+            Some(number) if self.line_numbers => ("gutter", number.to_string()),
+            Some(_) => ("gutter", String::new()),
+            None => ("gutter wrap", "↪".to_string()),
+        };
+        if self.number_column_width > 0 {
+            self.svg += &format!(
+                r#"<text y="{}em" class="{}{}" xml:space="preserve">{:>width$} </text>"#,
+                row,
+                gutter_class,
+                dimmed,
+                gutter,
+                width = self.number_column_width - 1,
+            );
+        }
         self.svg += &format!(
-            r#"<text y="{line}em" class="meta separator" xml:space="preserve">{:>3} </text><text x="48" y="{line}em" xml:space="preserve">"#,
-            self.current_line,
-            line = self.current_line,
+            r#"<text x="{}" y="{}em" class="code{}" xml:space="preserve">"#,
+            self.number_column_width as f32 * self.char_width(),
+            row,
+            dimmed,
         );
     }
 
@@ -120,33 +391,128 @@ where
         self.svg.push('>');
     }
 
+    /// Space around the code in a row: padding, and margin of the frame
+    fn horizontal_space(&self) -> usize {
+        2 * (self.padding + self.frame.outer_margin())
+    }
+
+    fn window_width(&self) -> usize {
+        self.picture_width
+            .saturating_sub(2 * self.frame.outer_margin())
+    }
+
+    /// Width available to the gutter and code
+    fn content_width(&self) -> usize {
+        self.picture_width.saturating_sub(self.horizontal_space())
+    }
+
+    /// Pick the font size, and the picture width if font size is fixed
+    fn fit(&mut self) {
+        let space = self.horizontal_space();
+        if let Some(font_size) = self.fixed_font_size {
+            let content_width = self.total_columns() as f32
+                * font_size as f32
+                * self.font_aspect_ratio;
+            let mut width = content_width.ceil() as usize + space;
+            width = width.max(self.min_width);
+            if self.max_width > 0 {
+                width = width.min(self.max_width);
+            }
+            self.picture_width = width.max(space + 1);
+            self.font_size = font_size;
+            if content_width.ceil() as usize <= self.content_width() {
+                return;
+            }
+        }
+        // Text too wide for the picture still gets the smallest size
+        // rather than none
+        self.font_size = ((self.content_width() as f32
+            / self.total_columns() as f32
+            / self.font_aspect_ratio) as usize)
+            .max(1);
+    }
+
+    fn open_group(&mut self, attributes: String) {
+        self.svg += &format!("<g {}>\n", attributes);
+        self.open_groups += 1;
+    }
+
     fn prologue(&mut self, stylesheet: String) {
-        let font_size = (self.picture_width as f32
-            / self.max_line_width as f32
-            / self.font_aspect_ratio) as usize;
+        self.layout();
+        self.fit();
+        let font_size = self.font_size;
         let (width, height) = self.get_picture_size();
         self.svg += &format!(
             r#"
 <svg viewBox="0 0 {} {}" xmlns="http://www.w3.org/2000/svg">
     <style>
         text {{
-          font-family: monospace;
+          font-family: {};
           font-size: {}px;
           fill: #FFFFFF;
+          {}
         }}
         {}
     </style>
-    <rect width="100%" height="100%" class="background" />
             "#,
-            width, height, font_size, stylesheet,
+            width,
+            height,
+            self.font_family,
+            font_size,
+            self.font_features,
+            stylesheet,
         )
         .trim();
         self.svg.push('\n');
+
+        let margin = self.frame.outer_margin();
+        let window_width = self.window_width();
+        let window_height = height.saturating_sub(2 * margin);
+        self.svg += &self.frame.defs(window_width, window_height);
+        if margin > 0 {
+            self.open_group(format!(
+                r#"transform="translate({m} {m})""#,
+                m = margin
+            ));
+        }
+        self.svg += &self.frame.window(window_width, window_height);
+        if self.frame.radius > 0 {
+            self.open_group(r#"clip-path="url(#window)""#.to_string());
+        }
+        self.svg += &self.frame.title_bar(window_width, font_size);
+        let title_bar_height = self.frame.title_bar_height(font_size);
+        if title_bar_height > 0 {
+            self.open_group(format!(
+                r#"transform="translate(0 {})""#,
+                title_bar_height
+            ));
+        }
+        if self.number_column_width > 0 {
+            self.svg += &format!(
+                r#"<rect width="{}" height="{}" class="gutter-background" />"#,
+                self.padding as f32
+                    + self.number_column_width as f32 * self.char_width(),
+                window_height.saturating_sub(title_bar_height),
+            );
+            self.svg.push('\n');
+        }
+        if self.padding > 0 {
+            self.open_group(format!(
+                r#"transform="translate({p} {p})""#,
+                p = self.padding
+            ));
+        }
         self.new_text();
     }
 
     fn epilogue(&mut self) {
-        self.svg += "</text></svg>";
+        self.svg += "</text>";
+        if self.is_hidden {
+            self.svg.truncate(self.line_start);
+        }
+        self.elided_rows();
+        self.svg += &"</g>".repeat(self.open_groups);
+        self.svg += "</svg>";
     }
 
     pub fn get_svg(&self) -> &str {
@@ -154,16 +520,84 @@ where
     }
 
     pub fn get_picture_size(&self) -> (usize, usize) {
+        // Leave room for descenders of the last row
+        let content_height =
+            (self.line_count as f32 + self.descender) * self.font_size as f32;
+        let frame_height = self.frame.title_bar_height(self.font_size)
+            + 2 * self.frame.outer_margin();
         (
             self.picture_width,
-            (self.picture_width as f32 / self.get_aspect_ratio()) as usize + 10,
+            content_height.ceil() as usize + 2 * self.padding + frame_height,
         )
     }
+}
+
+impl<'a, AttrFn> Visitor for SvgRenderer<'a, AttrFn>
+where
+    AttrFn: Fn(&Highlight) -> String,
+{
+    fn start_highlight(&mut self, highlight: Highlight) {
+        self.new_tspan(&highlight);
+        self.hl_stack.push(highlight);
+    }
+
+    fn end_highlight(&mut self) {
+        self.svg += "</tspan>";
+        self.hl_stack.pop();
+    }
 
-    pub fn get_aspect_ratio(&self) -> f32 {
-        let char_width = self.font_size as f32 * self.font_aspect_ratio;
-        let width = char_width * self.max_line_width as f32;
-        let height = self.font_size * self.line_count;
-        width as f32 / height as f32
+    fn text(&mut self, range: Range<usize>) {
+        if self.is_hidden {
+            return;
+        }
+        let source = self.source;
+        let text = self.tabs.expand(&source[range]);
+        for grapheme in text.graphemes(true) {
+            let width = grapheme_width(grapheme);
+            if self.wrap > 0
+                && self.column > 0
+                && self.column + width > self.wrap
+            {
+                self.close_text();
+                self.start_row(None);
+                self.reopen_tspans();
+            }
+            if self.split_glyphs && !grapheme.trim().is_empty() {
+                // Each absolutely positioned glyph is shaped separately
+                self.svg += &format!(
+                    r#"<tspan x="{}">{}</tspan>"#,
+                    (self.number_column_width + self.column) as f32
+                        * self.char_width(),
+                    encode_minimal(grapheme),
+                );
+            } else {
+                self.svg += &encode_minimal(grapheme);
+            }
+            self.column += width;
+        }
+    }
+
+    fn line_break(&mut self) {
+        self.tabs.new_line();
+        self.close_text();
+        if self.is_hidden {
+            self.svg.truncate(self.line_start);
+        }
+        self.new_text();
+        self.reopen_tspans();
+    }
+}
+
+/// Number of rows `line` takes when wrapped at `wrap` columns
+fn wrapped_rows(line: &str, wrap: usize) -> usize {
+    let mut rows = 1;
+    let mut column = 0;
+    for width in line.graphemes(true).map(grapheme_width) {
+        if column > 0 && column + width > wrap {
+            rows += 1;
+            column = 0;
+        }
+        column += width;
     }
+    rows
 }
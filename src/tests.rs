@@ -1,11 +1,34 @@
 use once_cell::sync::Lazy;
-use std::{fs, path::Path};
+use std::{collections::BTreeSet, fs, path::Path, sync::Arc};
 use tiny_skia::Pixmap;
 use tree_sitter::Parser;
-use tree_sitter_highlight::{Highlight, HighlightConfiguration, Highlighter};
+use tree_sitter_highlight::{
+    Highlight, HighlightConfiguration, HighlightEvent, Highlighter,
+};
 use usvg::{FitTo, Options, Tree};
 
-use crate::utils::get_language;
+use crate::batch::{self, Manifest};
+use crate::cache::RenderCache;
+use crate::config::{self, Config};
+use crate::fonts::{self, FontFeatures};
+use crate::frame::Frame;
+use crate::latex_renderer;
+use crate::line_ranges::{LineRanges, Row};
+use crate::lsp_renderer;
+use crate::metrics::str_width;
+use crate::pdf_renderer;
+use crate::raster::{self, RasterOptions};
+use crate::render::{Error, OutputFormat, RenderOptions, Renderer};
+use crate::rtf_renderer;
+use crate::snippets::{
+    self, DirStore, MemoryStore, Snippet, SnippetStore, SqliteStore,
+};
+use crate::styled_text;
+use crate::subset;
+use crate::theme::{parse_color, Theme};
+use crate::traversal;
+use crate::url_code;
+use crate::utils::{expand_tabs, get_language, TabExpander, USVG_TREE_OPTIONS};
 use crate::{
     stylesheet::Stylesheet, sublime_colors::SublimeColorScheme,
     svg_renderer::SvgRenderer,
@@ -67,8 +90,7 @@ fn highlight() {
     let source_code = fs::read_to_string(
         "/home/kraftwerk28/projects/haskell/playground/ShuntingYard.hs",
     )
-    .unwrap()
-    .replace('\t', "    ");
+    .unwrap();
     let language_name = "haskell";
 
     let mut hl_cfg = {
@@ -105,7 +127,7 @@ fn highlight() {
 
     let attribute_callback = |hl: &Highlight| svg_attributes[hl.0].clone();
     let mut svg_renderer =
-        SvgRenderer::new(source_code.clone(), &attribute_callback);
+        SvgRenderer::new(&source_code, &attribute_callback).with_tab_width(4);
 
     let stylesheet =
         fs::read_to_string("assets/stylesheets/ayu-vim.css").unwrap();
@@ -166,3 +188,647 @@ fn resvg() {
     println!("Saving png");
     pixmap.save_png(result_name.as_ref()).unwrap();
 }
+
+#[test]
+fn url_code_roundtrip() {
+    let source_code = "fn main() {\n    println!(\"Привіт\");\n}\n";
+    let encoded = url_code::encode(source_code);
+    assert!(!encoded.contains(&['+', '/', '='] as &[char]));
+    assert_eq!(url_code::decode(&encoded, 0).unwrap(), source_code);
+    assert!(url_code::decode(&encoded, 4).is_err());
+    assert!(url_code::decode("not base64!", 0).is_err());
+}
+
+#[test]
+fn snippet_store() {
+    let snippet = Snippet::new("rust".into(), "fn main() {}".into(), None);
+    let id = snippet.id();
+    assert!(snippets::is_valid_id(&id));
+    assert!(!snippets::is_valid_id("../../etc/passwd"));
+
+    let store = MemoryStore::default();
+    store.put(&id, &snippet).unwrap();
+    assert_eq!(store.get(&id).unwrap().unwrap().code, snippet.code);
+
+    let mut expired = Snippet::new("rust".into(), "loop {}".into(), Some(0));
+    expired.expires_at = Some(snippets::now() - 1);
+    store.put(&expired.id(), &expired).unwrap();
+    assert!(store.get(&expired.id()).unwrap().is_none());
+    assert_eq!(store.purge_expired().unwrap(), 1);
+}
+
+#[test]
+fn memory_store_limit() {
+    let snippets: Vec<_> = ["a", "b", "c"]
+        .iter()
+        .map(|code| Snippet::new("rust".into(), code.repeat(100), None))
+        .collect();
+    let size = snippets::ID_LENGTH + "rust".len() + 100;
+    let store = MemoryStore::default().with_max_bytes(2 * size);
+    store.put(&snippets[0].id(), &snippets[0]).unwrap();
+    store.put(&snippets[1].id(), &snippets[1]).unwrap();
+    // Reading the first snippet makes the second one the least recently used
+    assert!(store.get(&snippets[0].id()).unwrap().is_some());
+    store.put(&snippets[2].id(), &snippets[2]).unwrap();
+    assert!(store.get(&snippets[0].id()).unwrap().is_some());
+    assert!(store.get(&snippets[1].id()).unwrap().is_none());
+    assert!(store.get(&snippets[2].id()).unwrap().is_some());
+}
+
+#[test]
+fn snippet_expiry_merge() {
+    let dir = std::env::temp_dir().join("ts-highlight-snippet-merge");
+    let _ = fs::remove_dir_all(&dir);
+    let stores: Vec<Box<dyn SnippetStore>> = vec![
+        Box::new(MemoryStore::default()),
+        Box::new(DirStore::open(dir.join("dir")).unwrap()),
+        Box::new(SqliteStore::open(dir.join("snippets.db")).unwrap()),
+    ];
+    for store in stores {
+        let short =
+            Snippet::new("rust".into(), "fn main() {}".into(), Some(60));
+        let long =
+            Snippet::new("rust".into(), "fn main() {}".into(), Some(600));
+        let forever = Snippet::new("rust".into(), "fn main() {}".into(), None);
+        let id = short.id();
+
+        assert_eq!(store.put(&id, &long).unwrap().expires_at, long.expires_at);
+        // Saving again with a shorter lifetime keeps the longer one
+        let saved = store.put(&id, &short).unwrap();
+        assert_eq!(saved.expires_at, long.expires_at);
+        assert_eq!(
+            store.get(&id).unwrap().unwrap().expires_at,
+            saved.expires_at
+        );
+        // Snippets that never expire stay that way
+        assert_eq!(store.put(&id, &forever).unwrap().expires_at, None);
+        store.put(&id, &short).unwrap();
+        assert_eq!(store.get(&id).unwrap().unwrap().expires_at, None);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn theme_palette() {
+    assert_eq!(parse_color("#fa0"), Some([255, 170, 0, 255]));
+    assert_eq!(parse_color("#707a8c66"), Some([0x70, 0x7a, 0x8c, 0x66]));
+    assert_eq!(parse_color("red"), None);
+
+    let theme = Theme::from_css(
+        ".background { fill: #212733 } .guide { fill: #3D4751 }".into(),
+    );
+    assert_eq!(theme.gutter_foreground(), Some("#3D4751"));
+    assert_eq!(theme.gutter_background(), None);
+    assert!(theme.svg_stylesheet().contains(".gutter{fill:#3D4751;}"));
+}
+
+#[test]
+fn line_ranges() {
+    let lines = LineRanges::parse("3, 7-9").unwrap();
+    assert!(lines.contains(3) && lines.contains(8));
+    assert!(!lines.contains(4) && !lines.contains(10));
+    assert!(LineRanges::parse("").unwrap().is_empty());
+    assert!(LineRanges::parse("9-7").is_none());
+    assert!(LineRanges::parse("a-b").is_none());
+    assert_eq!(lines.to_string(), "3,7-9");
+
+    // Lines 1-10, numbered from 1
+    let rows = lines.rows(1, 10, false);
+    assert_eq!(rows[..2], [Row::Line(2), Row::Elided]);
+    assert_eq!(rows.len(), 5);
+    let rows = lines.rows(1, 10, true);
+    assert_eq!(rows.first(), Some(&Row::Elided));
+    assert_eq!(rows.last(), Some(&Row::Elided));
+    assert_eq!(LineRanges::default().rows(1, 3, true).len(), 3);
+}
+
+#[test]
+fn tab_expansion() {
+    assert_eq!(expand_tabs("\tx\n  \ty", 4), "    x\n    y");
+    assert_eq!(expand_tabs("ab\tc\td", 4), "ab  c   d");
+    assert_eq!(expand_tabs("ab\tc", 2), "ab  c");
+
+    // Tab stops are counted from the start of the line across pieces
+    let mut tabs = TabExpander::new(4);
+    assert_eq!(tabs.expand("ab"), "ab");
+    assert_eq!(tabs.expand("\tc"), "  c");
+    tabs.new_line();
+    assert_eq!(tabs.expand("\t"), "    ");
+
+    // Highlighted as written, expanded when drawn
+    let source = "a\tb\n\tc";
+    let events = vec![
+        HighlightEvent::Source { start: 0, end: 1 },
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 1, end: 3 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 3, end: 6 },
+    ];
+    let attributes = |_: &Highlight| String::new();
+    let mut svg_renderer =
+        SvgRenderer::new(source, &attributes).with_tab_width(4);
+    svg_renderer
+        .render(events.into_iter().map(Ok), String::new())
+        .unwrap();
+    let svg = svg_renderer.get_svg();
+    assert!(svg.contains("a<tspan>   b</tspan>"));
+    assert!(svg.contains(">    c</text>"));
+    assert!(!svg.contains('\t'));
+}
+
+#[test]
+fn text_width() {
+    assert_eq!(str_width("fn main"), 7);
+    assert_eq!(str_width("Привіт"), 6);
+    assert_eq!(str_width("漢字"), 4);
+    // Combining acute accent and a family emoji made of 4 code points
+    assert_eq!(str_width("e\u{301}"), 1);
+    assert_eq!(str_width("👨‍👩‍👧‍👦"), 2);
+    assert_eq!(expand_tabs("漢\tx", 4), "漢  x");
+}
+
+#[test]
+fn font_features() {
+    let settings = FontFeatures::parse_settings("ss01, -calt,cv01=2").unwrap();
+    assert_eq!(settings[1], ("calt".to_string(), 0));
+    assert!(FontFeatures::parse_settings("ligatures").is_none());
+    let features = FontFeatures {
+        ligatures: true,
+        settings,
+    };
+    assert!(features.disables_ligatures());
+    assert_eq!(
+        features.css(),
+        r#"font-feature-settings:"ss01" 1,"calt" 0,"cv01" 2;"#
+    );
+    assert_eq!(FontFeatures::default().css(), "");
+}
+
+#[test]
+fn font_subset() {
+    let data = fs::read("assets/fonts/JetBrainsMono-Regular.ttf").unwrap();
+    let chars = "fn".chars().collect();
+    let font = subset::subset(&data, 0, &chars).expect("TrueType font");
+    assert!(font.len() < data.len() / 2);
+
+    let face = ttf_parser::Face::from_slice(&font, 0).unwrap();
+    let bbox = |c| face.glyph_bounding_box(face.glyph_index(c).unwrap());
+    assert!(bbox('f').is_some());
+    // Still mapped, but without an outline
+    assert!(bbox('x').is_none());
+}
+
+#[test]
+fn palette_png() {
+    let mut pixmap = Pixmap::new(16, 16).unwrap();
+    pixmap.fill(tiny_skia::Color::from_rgba8(0x21, 0x27, 0x33, 255));
+    let options = RasterOptions {
+        palette: true,
+        ..RasterOptions::default()
+    };
+    let png = raster::encode(&pixmap, OutputFormat::Png, &options).unwrap();
+    let (_, reader) = png::Decoder::new(&png[..]).read_info().unwrap();
+    assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+}
+
+#[test]
+fn document_escaping() {
+    assert_eq!(
+        latex_renderer::escape(r"\x{}"),
+        r"\char92{}x\char123{}\char125{}"
+    );
+    assert_eq!(rtf_renderer::escape(r"{\}"), r"\{\\\}");
+    assert_eq!(rtf_renderer::escape("é"), r"\u233?");
+    // Surrogate pairs of signed code units
+    assert_eq!(rtf_renderer::escape("😀"), r"\u-10179?\u-8704?");
+}
+
+#[test]
+fn token_lines() {
+    let source = "let x\n  = 1;";
+    let events = vec![
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 0, end: 3 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 3, end: 8 },
+        HighlightEvent::HighlightStart(Highlight(1)),
+        HighlightEvent::Source { start: 8, end: 9 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 9, end: 12 },
+    ];
+    let lines =
+        styled_text::token_lines(events.into_iter().map(Ok), source).unwrap();
+    let ranges: Vec<Vec<_>> = lines
+        .iter()
+        .map(|line| {
+            line.iter()
+                .map(|token| (token.range.clone(), token.highlights.len()))
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        ranges,
+        vec![
+            vec![(0..3, 1), (3..5, 0)],
+            vec![(6..8, 0), (8..9, 1), (9..12, 0)],
+        ]
+    );
+}
+
+#[test]
+fn lsp_semantic_tokens() {
+    let source = "😀 fn\nx";
+    let events = vec![
+        HighlightEvent::Source { start: 0, end: 5 },
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 5, end: 7 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 7, end: 8 },
+        HighlightEvent::HighlightStart(Highlight(1)),
+        HighlightEvent::Source { start: 8, end: 9 },
+        HighlightEvent::HighlightEnd,
+    ];
+    let names = vec!["keyword".to_string(), "variable".to_string()];
+    let json = lsp_renderer::render(events.into_iter().map(Ok), source, &names)
+        .unwrap();
+    let tokens: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(tokens["legend"]["tokenTypes"], serde_json::json!(names));
+    // The emoji takes two UTF-16 code units
+    assert_eq!(
+        tokens["data"],
+        serde_json::json!([0, 3, 2, 0, 0, 1, 0, 1, 1, 0])
+    );
+}
+
+#[test]
+fn batch_duplicate_names() {
+    let manifest = Manifest::parse(
+        br#"{
+            "defaults": {"lang": "rust", "format": "svg"},
+            "items": [
+                {"name": "dir/a.svg", "code": "let a = 1;"},
+                {"name": "dir//a.svg", "code": "let b = 2;"},
+                {"name": "report.json", "code": "let c = 3;"},
+                {"code": "let d = 4;"}
+            ]
+        }"#,
+    )
+    .unwrap();
+    let outputs = batch::run(manifest, None, 2);
+    assert!(outputs[0].result.is_ok());
+    let err = outputs[1].result.as_ref().unwrap_err();
+    assert!(err.contains("duplicate"));
+    assert!(outputs[2].result.is_err());
+    assert_eq!(outputs[3].name, "3.svg");
+    assert!(outputs[3].result.is_ok());
+}
+
+#[test]
+fn config_env_overrides() {
+    use serde_json::json;
+    let level = config::parse_env_value("info,hyper=warn", &json!("info"));
+    assert_eq!(level, toml::Value::String("info,hyper=warn".into()));
+    let enabled = config::parse_env_value("png, svg", &json!(["png"]));
+    assert_eq!(enabled.as_array().map(Vec::len), Some(2));
+    assert_eq!(
+        config::parse_env_value("8080", &json!(80)),
+        toml::Value::Integer(8080)
+    );
+
+    // Variables are passed in rather than set, since other tests load the
+    // config from the environment at the same time
+    let var = |name: &str, value: &str| (name.to_string(), value.to_string());
+    let mut table = toml::value::Table::new();
+    let warnings = config::apply_env_overrides(
+        &mut table,
+        vec![
+            var("HIGHLIGHT_LOG_LEVEL", "info,hyper=warn"),
+            var("HIGHLIGHT_UNRELATED_SETTING", "1"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(warnings.len(), 1);
+    let config: Config = toml::Value::Table(table).try_into().unwrap();
+    assert_eq!(config.log.level, "info,hyper=warn");
+
+    // A bad value is reported with its variable
+    let err = config::apply_env_overrides(
+        &mut toml::value::Table::new(),
+        vec![var("HIGHLIGHT_LIMITS_MAX_LINES", "many")],
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("HIGHLIGHT_LIMITS_MAX_LINES"));
+}
+
+#[test]
+fn disk_cache_eviction() {
+    let dir = std::env::temp_dir().join("ts-highlight-disk-cache");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let cache = RenderCache::new(1, Some(dir.clone()), 12);
+    cache.insert("a", Arc::new(b"aaaaaa".to_vec()));
+    cache.insert("b", Arc::new(b"bbbbbb".to_vec()));
+    // Only `b` is in memory, reading `a` from disk makes `b` the least
+    // recently used file
+    assert!(cache.get("a").is_some());
+    cache.insert("c", Arc::new(b"cccccc".to_vec()));
+    assert!(dir.join("a").exists());
+    assert!(!dir.join("b").exists());
+    assert!(cache.get("b").is_none());
+
+    // Files already in the directory count towards the limit
+    let cache = RenderCache::new(1, Some(dir.clone()), 6);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    assert!(cache.get("a").is_some() ^ cache.get("c").is_some());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn option_bounds() {
+    let parse = |key: &str, value: &str| {
+        let mut params = std::collections::HashMap::new();
+        params.insert("lang".to_string(), "rust".to_string());
+        params.insert(key.to_string(), value.to_string());
+        RenderOptions::from_map(&params)
+    };
+    assert_eq!(parse("font_size", "256").unwrap().font_size, Some(256));
+    assert!(parse("font_size", "257").is_err());
+    assert!(parse("font_size", "0").is_err());
+    assert_eq!(parse("padding", "1024").unwrap().padding, 1024);
+    assert!(parse("padding", "100000000").is_err());
+    assert!(parse("margin", "100000000").is_err());
+    assert!(parse("scale", "9").is_err());
+}
+
+#[test]
+fn fit_width_min_font_size() {
+    // Content far wider than `max_width` allows
+    let png = Renderer::new("rust")
+        .format(OutputFormat::Png)
+        .font_size(12)
+        .width_bounds(0, 20)
+        .render(&"x".repeat(500))
+        .unwrap();
+    assert!(!png.is_empty());
+}
+
+#[test]
+fn frame_shadow_margin() {
+    let mut frame = Frame {
+        shadow: true,
+        ..Frame::default()
+    };
+    // The shadow needs room even without a margin
+    assert!(frame.outer_margin() >= 40);
+    assert!(frame
+        .defs(100, 50)
+        .contains(r#"filterUnits="userSpaceOnUse""#));
+    frame.margin = 100;
+    assert_eq!(frame.outer_margin(), 100);
+    frame.shadow = false;
+    frame.margin = 0;
+    assert_eq!(frame.outer_margin(), 0);
+}
+
+#[test]
+fn font_family_escaping() {
+    assert_eq!(fonts::css_string("Fira Code"), "'Fira Code'");
+    assert_eq!(fonts::css_string(r"O'Neil\Sans"), r"'O\'Neil\\Sans'");
+    assert_eq!(fonts::css_string("</style>&"), r"'\3c /style>\26 '");
+    assert!(fonts::font_family_list("It's").starts_with(r"'It\'s', "));
+}
+
+#[test]
+fn embedded_font_faces() {
+    let font = fs::read("assets/fonts/JetBrainsMono-Regular.ttf").unwrap();
+    let mut fontdb = usvg::fontdb::Database::new();
+    fontdb.load_font_data(font.clone());
+    let chars: BTreeSet<char> = "fn main 你".chars().collect();
+
+    // Families without faces are skipped, and fallbacks are only embedded
+    // for characters that the families before them lack
+    let css = fonts::font_face_rules(
+        &fontdb,
+        vec!["Missing", "JetBrains Mono", "JetBrains Mono"],
+        &chars,
+    );
+    assert_eq!(css.matches("@font-face").count(), 1);
+    assert!(css.contains(
+        "font-family:'JetBrains Mono';font-weight:400;font-style:normal;"
+    ));
+    assert!(css.contains("format('truetype')"));
+    let unsupported: BTreeSet<char> = "你".chars().collect();
+    assert_eq!(
+        fonts::font_face_rules(&fontdb, vec!["JetBrains Mono"], &unsupported),
+        ""
+    );
+
+    // Every face of a family is embedded
+    fontdb.load_font_data(font.clone());
+    let css = fonts::font_face_rules(&fontdb, vec!["JetBrains Mono"], &chars);
+    assert_eq!(css.matches("@font-face").count(), 2);
+
+    // CFF outlines can't be subset and are embedded whole
+    let (_, format) = fonts::embeddable_font(&font, 0, &chars).unwrap();
+    assert_eq!(format, "truetype");
+    let mut cff = b"OTTO".to_vec();
+    cff.extend_from_slice(&[0; 8]);
+    assert_eq!(
+        fonts::embeddable_font(&cff, 0, &chars),
+        Some((cff.clone(), "opentype"))
+    );
+}
+
+#[test]
+fn embed_font_option() {
+    let renderer = Renderer::new("rust").format(OutputFormat::Svg);
+    let render = |renderer: Renderer| {
+        String::from_utf8(renderer.render("fn main() {}").unwrap()).unwrap()
+    };
+    let svg = render(renderer.clone().embed_font(true));
+    assert!(svg.contains("@font-face"));
+    assert!(svg.contains("base64,"));
+    assert!(!render(renderer).contains("@font-face"));
+}
+
+#[test]
+fn text_as_paths_option() {
+    let svg = Renderer::new("rust")
+        .format(OutputFormat::Svg)
+        .text_as_paths(true)
+        .render("fn main() {\n    println!(\"hi\");\n}\n")
+        .unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(!svg.contains("<text"));
+    assert!(svg.contains("<path"));
+    let tree = Tree::from_data(svg.as_bytes(), &USVG_TREE_OPTIONS).unwrap();
+    assert!(tree.root().descendants().count() > 1);
+}
+
+#[test]
+fn pdf_unsupported_options() {
+    let renderer = Renderer::new("rust").format(OutputFormat::Pdf);
+    let source = "fn main() {}\n";
+    let rejected = |renderer: Renderer| match renderer.render(source) {
+        Err(Error::InvalidParameter(name, _)) => name,
+        other => panic!("expected an invalid parameter, got {:?}", other),
+    };
+    let shadow = Frame {
+        shadow: true,
+        ..Frame::default()
+    };
+    assert_eq!(rejected(renderer.clone().frame(shadow)), "shadow");
+    assert_eq!(rejected(renderer.clone().wrap(40)), "wrap");
+    let features = FontFeatures {
+        ligatures: true,
+        settings: vec![("zero".to_string(), 1)],
+    };
+    assert_eq!(
+        rejected(renderer.clone().font_features(features)),
+        "font_features"
+    );
+    // The title names the document instead
+    let titled = Frame {
+        title: Some("main.rs".to_string()),
+        ..Frame::default()
+    };
+    let pdf = renderer.frame(titled).render(source).unwrap();
+    assert!(pdf.starts_with(b"%PDF"));
+}
+
+#[test]
+fn pdf_text() {
+    let pdf = Renderer::new("rust")
+        .format(OutputFormat::Pdf)
+        .render("fn main() {}\n")
+        .unwrap();
+    assert!(pdf.starts_with(b"%PDF"));
+    // The font is embedded, and text is written as its glyph IDs in hex
+    let pdf = String::from_utf8_lossy(&pdf).to_uppercase();
+    assert!(pdf.contains("/FONTFILE"));
+    let font = fs::read("assets/fonts/JetBrainsMono-Regular.ttf").unwrap();
+    let face = ttf_parser::Face::from_slice(&font, 0).unwrap();
+    let glyphs: String = "main"
+        .chars()
+        .map(|c| format!("{:04X}", face.glyph_index(c).unwrap().0))
+        .collect();
+    assert!(pdf.contains(&glyphs));
+}
+
+#[test]
+fn pdf_page_size() {
+    let renderer = Renderer::new("rust").format(OutputFormat::Pdf);
+    let media_boxes = |renderer: &Renderer, source: &str| {
+        let pdf = renderer.render(source).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf).into_owned();
+        pdf.match_indices("/MediaBox")
+            .map(|(idx, _)| {
+                let end = idx + pdf[idx..].find(']').unwrap();
+                pdf[idx..end].to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+    // Only the lines kept count toward the width
+    let first_line = renderer
+        .clone()
+        .lines(LineRanges::parse("1").unwrap(), false);
+    assert_eq!(
+        media_boxes(&first_line, &format!("a\n{}\n", "b".repeat(200))),
+        media_boxes(&first_line, "a\nb\n"),
+    );
+    // 12pt rows don't fit a single 14400pt page
+    assert_eq!(media_boxes(&renderer, &"x\n".repeat(1300)).len(), 2);
+}
+
+#[test]
+fn pdf_font_runs() {
+    let cjk: BTreeSet<char> = "你好".chars().collect();
+    let emoji: BTreeSet<char> = "🦀".chars().collect();
+    let fallbacks = vec![(cjk, "cjk"), (emoji, "emoji")];
+    let runs = pdf_renderer::font_runs("a 你好🦀b", &"mono", &fallbacks);
+    let runs: Vec<_> =
+        runs.into_iter().map(|(run, font)| (run, *font)).collect();
+    assert_eq!(
+        runs,
+        vec![
+            ("a ", "mono"),
+            ("你好", "cjk"),
+            ("🦀", "emoji"),
+            ("b", "mono")
+        ]
+    );
+    assert!(pdf_renderer::font_runs("", &"mono", &fallbacks).is_empty());
+}
+
+#[test]
+fn palette_order() {
+    let (a, b, c) = ([9, 9, 9, 255], [0, 0, 0, 255], [0, 0, 1, 255]);
+    let rgba: Vec<u8> = [c, a, b, a, a].iter().flatten().copied().collect();
+    // By count, then by color
+    let (palette, pixels) = raster::quantize(&rgba);
+    assert_eq!(palette, vec![a, b, c]);
+    assert_eq!(pixels, vec![2, 0, 1, 0, 0]);
+}
+
+#[test]
+fn latex_output() {
+    let source = "a\nb\nc";
+    let events = || {
+        vec![
+            HighlightEvent::HighlightStart(Highlight(0)),
+            HighlightEvent::Source { start: 0, end: 1 },
+            HighlightEvent::HighlightEnd,
+            HighlightEvent::Source { start: 1, end: 5 },
+        ]
+        .into_iter()
+        .map(Ok)
+    };
+    let theme = Theme::from_css(
+        ".background { fill: #000000 } .link { text-decoration: underline }"
+            .into(),
+    );
+    let lines = LineRanges::parse("1,3").unwrap();
+    let render = |ellipsis: bool| {
+        let renderer = Renderer::new("rust").lines(lines.clone(), ellipsis);
+        latex_renderer::render(
+            events(),
+            source,
+            &["link".to_string()],
+            &theme,
+            renderer.options(),
+        )
+        .unwrap()
+    };
+    let latex = render(false);
+    assert!(latex.contains(r"\definecolor{hlbackground}{HTML}{000000}"));
+    assert!(latex.contains(r"\colorbox{hlbackground}{\BUseVerbatim{hlcode}}"));
+    assert!(latex.contains(r"\underline{a}"));
+    // Line numbers, a space and the code, in characters pdfLaTeX has
+    assert!(latex.contains("}{---}\n"));
+    assert!(latex.is_ascii());
+    assert!(!latex.contains(r"\ldots"));
+    assert!(render(true).contains(r"\ldots{}"));
+}
+
+#[test]
+fn traversal_crlf() {
+    // `\r\n` split between events is still a single line break
+    let source = "a\r\nb\r\nc\r";
+    let events = vec![
+        HighlightEvent::Source { start: 0, end: 2 },
+        HighlightEvent::Source { start: 2, end: 6 },
+        HighlightEvent::Source { start: 6, end: 8 },
+    ];
+    let lines =
+        styled_text::token_lines(events.into_iter().map(Ok), source).unwrap();
+    let ranges: Vec<Vec<_>> = lines
+        .iter()
+        .map(|line| line.iter().map(|token| token.range.clone()).collect())
+        .collect();
+    // A `\r` that doesn't end a line is text
+    assert_eq!(ranges, vec![vec![0..1], vec![3..4], vec![6..8]]);
+    assert_eq!(
+        traversal::lines(source).collect::<Vec<_>>(),
+        vec!["a", "b", "c\r"]
+    );
+}
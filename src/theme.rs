@@ -0,0 +1,431 @@
+use crate::config;
+use crate::sublime_colors::SublimeColorScheme;
+use serde::Serialize;
+use std::{collections::HashMap, fs, path::PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Style {
+    pub color: Option<String>,
+    pub background: Option<String>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+/// A style applied to elements having every class of any of the selectors
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub selectors: Vec<Vec<String>>,
+    pub style: Style,
+}
+
+/// Colors for highlight names along with global colors (background,
+/// foreground, gutter etc.), loaded either from a CSS file made for the
+/// SVG renderer or from a Sublime color scheme
+#[derive(Debug, Clone, Default)]
+pub struct Theme {
+    pub globals: HashMap<String, String>,
+    pub rules: Vec<Rule>,
+    /// Original stylesheet, used as-is for SVG output
+    css: Option<String>,
+}
+
+/// Single-class rules of CSS themes that define editor colors rather than
+/// highlight names, mapped to the global they stand for
+const PALETTE_CLASSES: &[(&str, &str)] = &[
+    ("gutter", "gutter"),
+    ("gutter_foreground", "gutter_foreground"),
+    ("guide", "gutter_foreground"),
+    ("line_highlight", "line_highlight"),
+    ("line", "line_highlight"),
+    ("selection", "selection"),
+    ("panel", "panel"),
+];
+
+/// Parse `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa` into RGBA components
+pub fn parse_color(color: &str) -> Option<[u8; 4]> {
+    let hex = color.trim().strip_prefix('#')?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digit = |idx: usize| u8::from_str_radix(&hex[idx..idx + 1], 16).ok();
+    let byte = |idx: usize| u8::from_str_radix(&hex[idx..idx + 2], 16).ok();
+    match hex.len() {
+        3 | 4 => {
+            let mut rgba = [255; 4];
+            for (idx, component) in rgba.iter_mut().take(hex.len()).enumerate()
+            {
+                *component = digit(idx)? * 17;
+            }
+            Some(rgba)
+        }
+        6 | 8 => {
+            let mut rgba = [255; 4];
+            for (idx, component) in
+                rgba.iter_mut().take(hex.len() / 2).enumerate()
+            {
+                *component = byte(idx * 2)?;
+            }
+            Some(rgba)
+        }
+        _ => None,
+    }
+}
+
+/// Blend a color with alpha over `background`, black if there's none, for
+/// outputs that can't do it themselves
+pub fn opaque_color(
+    color: &str,
+    background: Option<[u8; 4]>,
+) -> Option<[u8; 3]> {
+    let [r, g, b, a] = parse_color(color)?;
+    let [br, bg, bb, _] = background.unwrap_or([0, 0, 0, 255]);
+    let blend = |fg: u8, bg: u8| {
+        ((fg as u32 * a as u32 + bg as u32 * (255 - a as u32)) / 255) as u8
+    };
+    Some([blend(r, br), blend(g, bg), blend(b, bb)])
+}
+
+/// SVG paint for `color`. Colors with alpha are split into an opaque color
+/// and an opacity, since not every SVG renderer understands `#rrggbbaa`
+fn svg_paint(property: &str, color: &str) -> String {
+    match parse_color(color) {
+        Some([r, g, b, a]) if a < 255 => format!(
+            "{p}:#{:02x}{:02x}{:02x};{p}-opacity:{:.3};",
+            r,
+            g,
+            b,
+            a as f32 / 255.,
+            p = property
+        ),
+        _ => format!("{}:{};", property, color),
+    }
+}
+
+fn parse_declarations(declarations: &str) -> Style {
+    let mut style = Style::default();
+    for declaration in declarations.split(';') {
+        let mut kv = declaration.splitn(2, ':');
+        let (property, value) = match (kv.next(), kv.next()) {
+            (Some(property), Some(value)) => (property.trim(), value.trim()),
+            _ => continue,
+        };
+        match property {
+            "fill" | "color" => style.color = Some(value.to_string()),
+            "background" | "background-color" => {
+                style.background = Some(value.to_string())
+            }
+            "font-weight" => style.bold = value == "bold",
+            "font-style" => style.italic = value == "italic",
+            "text-decoration" => style.underline = value.contains("underline"),
+            _ => {}
+        }
+    }
+    style
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        result += &rest[..start];
+        rest = match rest[start..].find("*/") {
+            Some(end) => &rest[start + end + 2..],
+            None => "",
+        };
+    }
+    result + rest
+}
+
+impl Style {
+    pub(crate) fn merge(&mut self, other: &Style) {
+        if other.color.is_some() {
+            self.color = other.color.clone();
+        }
+        if other.background.is_some() {
+            self.background = other.background.clone();
+        }
+        self.bold |= other.bold;
+        self.italic |= other.italic;
+        self.underline |= other.underline;
+    }
+
+    /// CSS declarations, with `color_property` being either `fill` or `color`
+    pub fn declarations(&self, color_property: &str) -> String {
+        let mut decl = String::new();
+        if let Some(color) = &self.color {
+            decl += &match color_property {
+                "fill" => svg_paint(color_property, color),
+                _ => format!("{}:{};", color_property, color),
+            };
+        }
+        if self.bold {
+            decl += "font-weight:bold;";
+        }
+        if self.italic {
+            decl += "font-style:italic;";
+        }
+        if self.underline {
+            decl += "text-decoration:underline;";
+        }
+        decl
+    }
+}
+
+impl Theme {
+    /// File of the theme in the stylesheets directory, either `<name>.css` or
+    /// `<name>.sublime-color-scheme`
+    pub fn path(name: &str) -> Option<PathBuf> {
+        if name.contains(&['/', '\\'] as &[char]) || name.starts_with('.') {
+            return None;
+        }
+        let dir = config::get().stylesheets_dir();
+        ["css", "sublime-color-scheme"]
+            .iter()
+            .map(|extension| dir.join(format!("{}.{}", name, extension)))
+            .find(|path| path.is_file())
+    }
+
+    /// Load a theme from the stylesheets directory, see [`Theme::path`]
+    pub fn load(name: &str) -> Option<Self> {
+        let path = Self::path(name)?;
+        let raw = fs::read_to_string(&path).ok()?;
+        if path.extension().map_or(false, |ext| ext == "css") {
+            return Some(Self::from_css(raw));
+        }
+        Some(SublimeColorScheme::parse(&raw).ok()?.to_theme())
+    }
+
+    pub fn new(globals: HashMap<String, String>, rules: Vec<Rule>) -> Self {
+        Self {
+            globals,
+            rules,
+            css: None,
+        }
+    }
+
+    /// Parse a stylesheet written for the SVG renderer. Only class selectors
+    /// are understood, along with `.background` and `text` for global colors
+    pub fn from_css(css: String) -> Self {
+        let mut globals = HashMap::new();
+        let mut rules = Vec::new();
+        for block in strip_comments(&css).split('}') {
+            let mut parts = block.splitn(2, '{');
+            let (selectors, declarations) = match (parts.next(), parts.next()) {
+                (Some(selectors), Some(declarations)) => {
+                    (selectors, declarations)
+                }
+                _ => continue,
+            };
+            let style = parse_declarations(declarations);
+            let mut class_selectors = Vec::new();
+            for selector in selectors.split(',').map(str::trim) {
+                match selector {
+                    "text" | "body" => {
+                        if let Some(color) = &style.color {
+                            globals.insert("foreground".into(), color.clone());
+                        }
+                    }
+                    ".background" => {
+                        if let Some(color) = &style.color {
+                            globals.insert("background".into(), color.clone());
+                        }
+                    }
+                    _ if selector.starts_with('.')
+                        && !selector.contains(char::is_whitespace) =>
+                    {
+                        let palette = PALETTE_CLASSES
+                            .iter()
+                            .find(|(class, _)| selector[1..] == **class);
+                        if let (Some((_, global)), Some(color)) =
+                            (palette, &style.color)
+                        {
+                            globals.insert(global.to_string(), color.clone());
+                        }
+                        class_selectors.push(
+                            selector
+                                .split('.')
+                                .filter(|class| !class.is_empty())
+                                .map(String::from)
+                                .collect(),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            if !class_selectors.is_empty() {
+                rules.push(Rule {
+                    selectors: class_selectors,
+                    style,
+                });
+            }
+        }
+        Self {
+            globals,
+            rules,
+            css: Some(css),
+        }
+    }
+
+    pub fn global(&self, name: &str) -> Option<&str> {
+        self.globals.get(name).map(String::as_str)
+    }
+
+    /// Color of line numbers, falling back to the foreground
+    pub fn gutter_foreground(&self) -> Option<&str> {
+        self.global("gutter_foreground")
+            .or_else(|| self.global("foreground"))
+    }
+
+    /// Background of the gutter, `None` if it's the same as of the code
+    pub fn gutter_background(&self) -> Option<&str> {
+        self.global("gutter")
+            .filter(|&color| Some(color) != self.global("background"))
+    }
+
+    /// Background band behind highlighted lines
+    pub fn line_highlight(&self) -> Option<&str> {
+        self.global("selection")
+            .or_else(|| self.global("line_highlight"))
+    }
+
+    /// Rules for the gutter and highlighted lines, which don't depend on
+    /// highlight names
+    fn editor_stylesheet(&self, color_property: &str) -> String {
+        let style = Style {
+            color: self.gutter_foreground().map(String::from),
+            ..Style::default()
+        };
+        let mut css =
+            format!(".gutter{{{}}}", style.declarations(color_property));
+        let band = self.line_highlight();
+        if color_property == "fill" {
+            css += &format!(
+                ".gutter-background{{{}}}",
+                self.gutter_background()
+                    .map_or("fill:none;".to_string(), |c| svg_paint("fill", c))
+            );
+            css += &format!(
+                ".line-highlight{{{}}}",
+                band.map_or("fill:none;".to_string(), |c| svg_paint("fill", c))
+            );
+            css += ".dimmed{fill-opacity:0.4;}";
+            if let Some(color) = self.gutter_foreground() {
+                css += &format!(
+                    ".separator{{{}stroke-dasharray:4 4;}}",
+                    svg_paint("stroke", color)
+                );
+                css += &format!(
+                    ".title-buttons{{fill:none;{}stroke-width:1.5;}}",
+                    svg_paint("stroke", color)
+                );
+                css +=
+                    &format!(".window-title{{{}}}", svg_paint("fill", color));
+            }
+            css += &format!(
+                ".title-bar{{{}}}",
+                self.global("panel")
+                    .or_else(|| self.gutter_background())
+                    .map_or("fill:none;".to_string(), |c| svg_paint("fill", c))
+            );
+        } else {
+            if let Some(color) = self.gutter_background() {
+                css += &format!(".gutter{{background:{};}}", color);
+            }
+            if let Some(color) = band {
+                css += &format!(".line-highlight{{background:{};}}", color);
+            }
+            css += ".line-highlight{display:inline-block;min-width:100%;}";
+            css += ".dimmed{opacity:0.4;}";
+            css += ".separator{display:inline-block;width:100%;";
+            css += "vertical-align:middle;border-top:1px dashed;}";
+            if let Some(color) = self.gutter_foreground() {
+                css += &format!(".separator{{border-color:{};}}", color);
+            }
+        }
+        css
+    }
+
+    /// Resolve the style of a highlight name like `function.method`. Rules
+    /// match when all of their classes are parts of the name; later and more
+    /// specific rules take precedence, as in CSS
+    pub fn style_for(&self, highlight_name: &str) -> Style {
+        let parts: Vec<_> = highlight_name.split('.').collect();
+        let mut matches: Vec<_> = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter_map(|(order, rule)| {
+                rule.selectors
+                    .iter()
+                    .filter(|classes| {
+                        classes.iter().all(|class| parts.contains(&&class[..]))
+                    })
+                    .map(Vec::len)
+                    .max()
+                    .map(|specificity| (specificity, order, rule))
+            })
+            .collect();
+        matches.sort_by_key(|(specificity, order, _)| (*specificity, *order));
+        let mut style = Style::default();
+        for (_, _, rule) in matches {
+            style.merge(&rule.style);
+        }
+        style
+    }
+
+    fn build_stylesheet(&self, color_property: &str) -> String {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                let declarations = rule.style.declarations(color_property);
+                if declarations.is_empty() {
+                    return None;
+                }
+                let selector = rule
+                    .selectors
+                    .iter()
+                    .map(|classes| format!(".{}", classes.join(".")))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                Some(format!("{}{{{}}}", selector, declarations))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Stylesheet for the SVG renderer, which uses `fill` instead of `color`
+    /// and highlight names split into separate classes
+    pub fn svg_stylesheet(&self) -> String {
+        if let Some(css) = &self.css {
+            return format!("{}\n{}", css, self.editor_stylesheet("fill"));
+        }
+        let mut basic = String::new();
+        if let Some(color) = self.global("background") {
+            basic += &format!(".background{{fill:{};}}\n", color);
+        }
+        if let Some(color) = self.global("foreground") {
+            basic += &format!("text{{fill:{};}}\n", color);
+        }
+        basic += &self.editor_stylesheet("fill");
+        basic.push('\n');
+        basic + &self.build_stylesheet("fill")
+    }
+
+    /// Stylesheet for HTML output, rooted at the `pre.highlight` element.
+    /// `font` holds declarations of the font family and features
+    pub fn html_stylesheet(&self, font: &str) -> String {
+        let mut basic = "pre.highlight{".to_string();
+        basic += font;
+        if let Some(color) = self.global("background") {
+            basic += &format!("background:{};", color);
+        }
+        if let Some(color) = self.global("foreground") {
+            basic += &format!("color:{};", color);
+        }
+        basic += "}\n";
+        basic += &self.editor_stylesheet("color");
+        basic += ".gutter{user-select:none;}\n";
+        basic + &self.build_stylesheet("color")
+    }
+}
@@ -0,0 +1,68 @@
+//! Walk of highlight events shared by every renderer, so that they agree on
+//! where lines end. Nested highlights form a stack, and highlights open at
+//! the end of a line carry on to the next one. Lines end at `\n`, and a `\r`
+//! right before it is left out, wherever the events split the source
+
+use std::ops::Range;
+use tree_sitter_highlight::{Error, Highlight, HighlightEvent};
+
+/// Receives the highlighted source from [`walk`]
+pub trait Visitor {
+    fn start_highlight(&mut self, highlight: Highlight);
+    fn end_highlight(&mut self);
+    /// Source text within a line, given by its byte range
+    fn text(&mut self, range: Range<usize>);
+    /// End of the current line. Highlights stay open
+    fn line_break(&mut self);
+}
+
+/// Byte range of `start..end` without a `\r` that ends a line
+fn trim_cr(source: &str, start: usize, end: usize) -> Range<usize> {
+    let ends_line = source[end..].starts_with('\n');
+    if ends_line && source[start..end].ends_with('\r') {
+        start..end - 1
+    } else {
+        start..end
+    }
+}
+
+/// Feed highlight events over `source` to `visitor`, stopping at the first
+/// error
+pub fn walk<I, V>(events: I, source: &str, visitor: &mut V) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+    V: Visitor + ?Sized,
+{
+    for event in events {
+        match event? {
+            HighlightEvent::HighlightStart(hl) => visitor.start_highlight(hl),
+            HighlightEvent::HighlightEnd => visitor.end_highlight(),
+            HighlightEvent::Source { start, end } => {
+                let mut offset = start;
+                for (idx, chunk) in source[start..end].split('\n').enumerate() {
+                    if idx > 0 {
+                        visitor.line_break();
+                    }
+                    let range = trim_cr(source, offset, offset + chunk.len());
+                    offset += chunk.len() + 1;
+                    if !range.is_empty() {
+                        visitor.text(range);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lines of `source` as [`walk`] splits them
+pub fn lines(source: &str) -> impl Iterator<Item = &str> {
+    let mut lines = source.split('\n').peekable();
+    std::iter::from_fn(move || {
+        let line = lines.next()?;
+        Some(match lines.peek() {
+            Some(_) => line.strip_suffix('\r').unwrap_or(line),
+            None => line,
+        })
+    })
+}
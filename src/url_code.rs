@@ -0,0 +1,70 @@
+//! Encoding of source code for the `code` parameter of `GET /render`: raw
+//! deflate, then unpadded URL-safe base64.
+//!
+//! Browsers handle URLs up to about 32 KiB and most proxies and CDNs cut them
+//! at 8 KiB, which is also the default of `limits.max_url_length`. Code
+//! usually compresses 3-4 times, so snippets of up to ~20 KiB fit.
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Base64(base64::DecodeError),
+    Deflate(io::Error),
+    TooLarge(usize),
+    Utf8,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Base64(err) => write!(f, "invalid base64url: {}", err),
+            Self::Deflate(err) => write!(f, "invalid deflate stream: {}", err),
+            Self::TooLarge(limit) => {
+                write!(f, "decompressed code exceeds {} bytes", limit)
+            }
+            Self::Utf8 => write!(f, "decompressed code is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode(source_code: &str) -> String {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder
+        .write_all(source_code.as_bytes())
+        .expect("Writing to Vec never fails");
+    let compressed = encoder.finish().expect("Writing to Vec never fails");
+    base64::encode_config(compressed, base64::URL_SAFE_NO_PAD)
+}
+
+/// Decode the `code` parameter. Decompressed size is bounded by `limit`
+/// bytes, unless it's 0
+pub fn decode(encoded: &str, limit: usize) -> Result<String, DecodeError> {
+    let compressed = base64::decode_config(
+        encoded.trim_end_matches('='),
+        base64::URL_SAFE_NO_PAD,
+    )
+    .map_err(DecodeError::Base64)?;
+    let mut decoder = DeflateDecoder::new(compressed.as_slice());
+    let mut bytes = Vec::new();
+    if limit > 0 {
+        decoder
+            .take(limit as u64 + 1)
+            .read_to_end(&mut bytes)
+            .map_err(DecodeError::Deflate)?;
+        if bytes.len() > limit {
+            return Err(DecodeError::TooLarge(limit));
+        }
+    } else {
+        decoder
+            .read_to_end(&mut bytes)
+            .map_err(DecodeError::Deflate)?;
+    }
+    String::from_utf8(bytes).map_err(|_| DecodeError::Utf8)
+}
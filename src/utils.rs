@@ -1,6 +1,10 @@
+use crate::config;
+use crate::fonts;
+use crate::metrics::grapheme_width;
 use once_cell::sync::Lazy;
-use std::fs;
+use std::{borrow::Cow, fs, path::Path};
 use tree_sitter::Language;
+use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug)]
 pub struct LanguageConfig {
@@ -88,11 +92,17 @@ pub static USVG_TREE_OPTIONS: Lazy<usvg::Options> = Lazy::new(|| {
     log::info!("Initializing usvg options...");
     tree_opts.image_rendering = usvg::ImageRendering::OptimizeSpeed;
     tree_opts.shape_rendering = usvg::ShapeRendering::OptimizeSpeed;
+    let config = config::get();
+    tree_opts.font_family = config.assets.font_family.clone();
+    tree_opts.fontdb = fonts::load_database(config);
     tree_opts
-        .fontdb
-        .load_font_file("assets/fonts/JetBrainsMono-Regular.ttf")
-        .unwrap();
-    tree_opts.fontdb.set_monospace_family("JetBrains Mono");
+});
+
+/// Options for vector output, without the speed trade-offs of rasterizing
+pub static USVG_VECTOR_OPTIONS: Lazy<usvg::Options> = Lazy::new(|| {
+    let mut tree_opts = USVG_TREE_OPTIONS.clone();
+    tree_opts.image_rendering = usvg::ImageRendering::OptimizeQuality;
+    tree_opts.shape_rendering = usvg::ShapeRendering::GeometricPrecision;
     tree_opts
 });
 
@@ -124,3 +134,73 @@ pub fn get_language(name: &str) -> Option<&Lazy<LanguageConfig>> {
         }
     })
 }
+
+/// Guess language name by file extension
+pub fn language_for_path(path: &Path) -> Option<&'static str> {
+    let name = match path.extension()?.to_str()? {
+        "js" | "jsx" | "mjs" | "cjs" => "javascript",
+        "c" | "h" => "c",
+        "cpp" | "cc" | "cxx" | "hpp" | "hh" | "hxx" => "cpp",
+        "rs" => "rust",
+        "py" => "python",
+        "hs" => "haskell",
+        "ts" => "typescript",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Replace tabs with spaces up to the next multiple of `tab_width` columns,
+/// so that tabbed alignment looks the same in every output format
+pub fn expand_tabs(source: &str, tab_width: usize) -> Cow<str> {
+    TabExpander::new(tab_width).expand(source)
+}
+
+/// Expands tabs like [`expand_tabs`] in text that is drawn piece by piece,
+/// keeping track of the column within the line
+#[derive(Debug, Clone, Copy)]
+pub struct TabExpander {
+    tab_width: usize,
+    column: usize,
+}
+
+impl TabExpander {
+    pub fn new(tab_width: usize) -> Self {
+        Self {
+            tab_width,
+            column: 0,
+        }
+    }
+
+    /// Expand tabs in `text`, which continues the current line
+    pub fn expand<'a>(&mut self, text: &'a str) -> Cow<'a, str> {
+        let has_tabs = text.contains('\t') && self.tab_width > 0;
+        let mut result = String::new();
+        for grapheme in text.graphemes(true) {
+            if grapheme == "\t" && self.tab_width > 0 {
+                let spaces = self.tab_width - self.column % self.tab_width;
+                result.extend(std::iter::repeat(' ').take(spaces));
+                self.column += spaces;
+                continue;
+            }
+            if has_tabs {
+                result += grapheme;
+            }
+            if grapheme.ends_with('\n') {
+                self.column = 0;
+            } else {
+                self.column += grapheme_width(grapheme);
+            }
+        }
+        if has_tabs {
+            Cow::Owned(result)
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Start over at the beginning of a line
+    pub fn new_line(&mut self) {
+        self.column = 0;
+    }
+}